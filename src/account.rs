@@ -1,41 +1,191 @@
+use crate::ids::ClientId;
 use anyhow::{anyhow, Result};
 use rust_decimal::Decimal;
+use std::fmt;
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Operation {
     Deposit,
     Withdraw,
     Dispute,
     Resolve,
     Chargeback,
+    /// Undo a deposit entered in error
+    ReverseDeposit,
+    /// Undo a withdrawal entered in error
+    ReverseWithdrawal,
+    /// Hold funds for a card payment
+    Authorize,
+    /// Take held funds of an authorization out of the account
+    Capture,
+    /// Give held funds of an authorization back
+    Release,
+    /// Fee paid by the client for another operation
+    Fee,
+    /// Fee credited to the house account
+    FeeIncome,
+    /// Interest credited on available funds
+    Interest,
+}
+
+/// Operations are displayed as the transaction type in the input
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operation::Deposit => "deposit",
+            Operation::Withdraw => "withdrawal",
+            Operation::Dispute => "dispute",
+            Operation::Resolve => "resolve",
+            Operation::Chargeback => "chargeback",
+            Operation::ReverseDeposit | Operation::ReverseWithdrawal => "reversal",
+            Operation::Authorize => "authorize",
+            Operation::Capture => "capture",
+            Operation::Release => "release",
+            Operation::Fee => "fee",
+            Operation::FeeIncome => "fee_income",
+            Operation::Interest => "interest",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Decides, per operation type, what is still allowed on a locked account.
+///
+/// By default only resolves and chargebacks go through, so disputes that were open before the
+/// account got locked can still be settled and held funds are not stranded forever.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LockPolicy {
+    pub deposit: bool,
+    pub withdraw: bool,
+    pub dispute: bool,
+    pub resolve: bool,
+    pub chargeback: bool,
+    pub reversal: bool,
+    pub authorize: bool,
+    pub capture: bool,
+    pub release: bool,
+}
+
+impl Default for LockPolicy {
+    fn default() -> Self {
+        Self {
+            deposit: false,
+            withdraw: false,
+            dispute: false,
+            resolve: true,
+            chargeback: true,
+            reversal: false,
+            authorize: false,
+            capture: true,
+            release: true,
+        }
+    }
+}
+
+impl LockPolicy {
+    /// Policy rejecting every operation on locked accounts
+    pub fn deny_all() -> Self {
+        Self {
+            deposit: false,
+            withdraw: false,
+            dispute: false,
+            resolve: false,
+            chargeback: false,
+            reversal: false,
+            authorize: false,
+            capture: false,
+            release: false,
+        }
+    }
+
+    /// Whether `operation` can be run on a locked account
+    pub fn allows(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::Deposit => self.deposit,
+            Operation::Withdraw => self.withdraw,
+            Operation::Dispute => self.dispute,
+            Operation::Resolve => self.resolve,
+            Operation::Chargeback => self.chargeback,
+            Operation::ReverseDeposit | Operation::ReverseWithdrawal => self.reversal,
+            Operation::Authorize => self.authorize,
+            Operation::Capture => self.capture,
+            Operation::Release => self.release,
+            // Fees are part of an operation that was already allowed
+            Operation::Fee | Operation::FeeIncome => true,
+            // Locked accounts earn no interest
+            Operation::Interest => false,
+        }
+    }
+
+    /// Enable `operation` on locked accounts
+    pub fn allow(&mut self, operation: Operation) {
+        let flag = match operation {
+            Operation::Deposit => &mut self.deposit,
+            Operation::Withdraw => &mut self.withdraw,
+            Operation::Dispute => &mut self.dispute,
+            Operation::Resolve => &mut self.resolve,
+            Operation::Chargeback => &mut self.chargeback,
+            Operation::ReverseDeposit | Operation::ReverseWithdrawal => &mut self.reversal,
+            Operation::Authorize => &mut self.authorize,
+            Operation::Capture => &mut self.capture,
+            Operation::Release => &mut self.release,
+            Operation::Fee | Operation::FeeIncome => return, // Always allowed
+            Operation::Interest => return,                   // Never allowed
+        };
+        *flag = true;
+    }
+}
+
+/// Rules shared by all the accounts handled by an engine
+#[derive(Clone, Debug, Default)]
+pub struct AccountPolicy {
+    pub locked: LockPolicy,
+    /// Disputes may hold more than available, driving it negative, so a chargeback on
+    /// already spent funds is not lost but recorded as a debt (negative `total`).
+    pub allow_negative: bool,
 }
 
 /// Client's account
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Account {
-    pub id: u16,
+    pub id: ClientId,
     pub locked: bool,
     pub total: Decimal,
     pub available: Decimal,
     pub held: Decimal,
+    /// Approved overdraft: how far below zero `available` may go
+    pub overdraft: Decimal,
 }
 
 impl Account {
-    pub fn new(id: u16) -> Self {
+    pub fn new(id: ClientId) -> Self {
         Self {
             id,
             locked: false,
             total: Decimal::ZERO,
             available: Decimal::ZERO,
             held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
         }
     }
 
+    /// Approve an overdraft of `overdraft` for the account
+    pub fn with_overdraft(mut self, overdraft: Decimal) -> Self {
+        self.overdraft = overdraft;
+        self
+    }
+
     /// This is the main interface for account operations. Most of the checks are run here.
     ///
-    /// This function runs the underlying operations only if Account is not locked and `amount`
-    /// is non-negative.
-    pub fn execute(&mut self, operation: Operation, amount: Decimal) -> Result<()> {
-        if self.locked {
+    /// This function runs the underlying operations only if `amount` is non-negative and, when
+    /// Account is locked, only if `policy` allows `operation` on locked accounts.
+    pub fn execute(
+        &mut self,
+        operation: Operation,
+        amount: Decimal,
+        policy: &AccountPolicy,
+    ) -> Result<()> {
+        if self.locked && !policy.locked.allows(operation) {
             return Err(anyhow!("Account is locked"));
         }
 
@@ -46,12 +196,46 @@ impl Account {
         match operation {
             Operation::Deposit => self.deposit(amount),
             Operation::Withdraw => self.withdraw(amount),
-            Operation::Dispute => self.dispute(amount),
+            Operation::Dispute => self.dispute(amount, policy.allow_negative),
             Operation::Resolve => self.resolve(amount),
             Operation::Chargeback => self.chargeback(amount),
+            // Reversals have the opposite balance effect of the original transaction
+            Operation::ReverseDeposit => self.withdraw(amount),
+            Operation::ReverseWithdrawal => self.deposit(amount),
+            Operation::Authorize => self.authorize(amount),
+            Operation::Capture => self.capture(amount),
+            Operation::Release => self.resolve(amount),
+            Operation::Fee => self.pay_fee(amount, policy.allow_negative),
+            Operation::FeeIncome | Operation::Interest => self.deposit(amount),
         }
     }
 
+    /// Run `operation` and charge `fee` for it, all or nothing. Returns the fee actually charged.
+    ///
+    /// Available funds (plus the overdraft) must cover both a withdrawal and its fee. The fee of
+    /// other operations (e.g. a chargeback) cannot make them fail: it is capped at what the account
+    /// can still pay, unless `policy` allows negative balances.
+    pub fn execute_with_fee(
+        &mut self,
+        operation: Operation,
+        amount: Decimal,
+        fee: Decimal,
+        policy: &AccountPolicy,
+    ) -> Result<Decimal> {
+        let before = self.clone();
+        self.execute(operation, amount, policy)?;
+        let fee = if operation == Operation::Withdraw || policy.allow_negative {
+            fee
+        } else {
+            fee.min(self.spendable().max(Decimal::ZERO))
+        };
+        if let Err(err) = self.execute(Operation::Fee, fee, policy) {
+            *self = before;
+            return Err(err);
+        }
+        Ok(fee)
+    }
+
     /// Add `amount` to client's balance.
     ///
     /// Total and available funds will increase.
@@ -71,20 +255,21 @@ impl Account {
 
     /// Subtract `amount` to client's balance.
     ///
-    /// Total and available funds will decrease.
-    /// This function returns an error if `amount` is greater than available funds.
+    /// Total and available funds will decrease, available going negative within the overdraft.
+    /// This function returns an error if `amount` is greater than available funds plus the overdraft.
     /// It does not overflow.
     ///
     /// # Warning
     /// This function should be used through the `execute` interface only.
     fn withdraw(&mut self, amount: Decimal) -> Result<()> {
         // Are there enough funds?
-        if amount > self.available {
+        if amount > self.spendable() {
             return Err(anyhow!("Insufficient funds"));
         }
 
-        // By design, this can never overflow: fields are always ensured to be non-negative, and
-        // we already checked `amount` is not bigger than `available`. It's safe to use `-=`
+        // By design, this can never overflow: we already checked `amount` is not bigger than
+        // `available` plus the overdraft, and the overdraft is not bigger than the largest Decimal.
+        // It's safe to use `-=`
         self.total -= amount;
         self.available -= amount;
         Ok(())
@@ -93,7 +278,8 @@ impl Account {
     /// Dispute a (deposit) transaction
     ///
     /// Held funds will increase by the amount specified, and available will decrease, so total will stay the same.
-    /// This function returns an error if `amount` is greater than available funds.
+    /// This function returns an error if `amount` is greater than available funds and the overdraft,
+    /// unless `allow_negative` is set, in which case available can go negative without bounds.
     ///
     /// # Warning
     /// This function should be used through the `execute` interface only.
@@ -101,19 +287,36 @@ impl Account {
     /// # Note
     /// My understanding from the assignment text is that the only things you can dispute are deposits.
     /// It's an error to dispute more than available is also another assumption of mine. See README
-    fn dispute(&mut self, amount: Decimal) -> Result<()> {
+    fn dispute(&mut self, amount: Decimal, allow_negative: bool) -> Result<()> {
         // Are there enough funds?
-        if amount > self.available {
+        if !allow_negative && amount > self.spendable() {
             return Err(anyhow!("Insufficient funds"));
         }
 
-        // I am not checking for overflows. The assumption is that `held` cannot get greater than available
-        self.held += amount;
-        self.available -= amount;
+        // When available can go negative, `held` is no longer bounded by it: beware of overflows
+        let available = self
+            .available
+            .checked_sub(amount)
+            .ok_or(anyhow!("Overflow"))?;
+        self.held = self.held.checked_add(amount).ok_or(anyhow!("Overflow"))?;
+        self.available = available;
 
         Ok(())
     }
 
+    /// Authorize a card payment: hold funds like a dispute does, but without ever overdrawing the
+    /// account, be it within its overdraft.
+    ///
+    /// # Warning
+    /// This function should be used through the `execute` interface only.
+    fn authorize(&mut self, amount: Decimal) -> Result<()> {
+        if amount > self.available {
+            return Err(anyhow!("Insufficient funds"));
+        }
+        // Checked above: available stays non-negative
+        self.dispute(amount, true)
+    }
+
     /// Resolve a (deposit) transaction
     ///
     /// This function does reverse `dispute`.
@@ -149,13 +352,106 @@ impl Account {
             return Err(anyhow!("Insufficient held funds"));
         }
 
-        // By design, this can never overflow: fields are always ensured to be non-negative, and
-        // we already checked `amount` is not bigger than `available`. It's safe to use `-=`
+        // By design, this can never overflow: `held` is non-negative and `total` is `available + held`,
+        // so it can get at most as negative as `available`. It's safe to use `-=`
         self.total -= amount;
         self.held -= amount;
         self.locked = true;
         Ok(())
     }
+
+    /// Capture `amount` out of held funds, ending (part of) an authorization.
+    ///
+    /// Total and held funds will decrease, but unlike a chargeback the account is not locked.
+    /// This function returns an error if `amount` is greater than held funds.
+    /// It does not overflow.
+    ///
+    /// # Warning
+    /// This function should be used through the `execute` interface only.
+    fn capture(&mut self, amount: Decimal) -> Result<()> {
+        // Are there enough held funds?
+        if amount > self.held {
+            return Err(anyhow!("Insufficient held funds"));
+        }
+
+        // Same as chargeback, it's safe to use `-=`
+        self.total -= amount;
+        self.held -= amount;
+        Ok(())
+    }
+
+    /// Subtract a fee from client's balance.
+    ///
+    /// Same as a withdrawal, except available can go negative without bounds if `allow_negative` is set.
+    ///
+    /// # Warning
+    /// This function should be used through the `execute_with_fee` interface only.
+    fn pay_fee(&mut self, amount: Decimal, allow_negative: bool) -> Result<()> {
+        // Are there enough funds?
+        if !allow_negative && amount > self.spendable() {
+            return Err(anyhow!("Insufficient funds"));
+        }
+
+        // When available can go negative, beware of overflows
+        let available = self
+            .available
+            .checked_sub(amount)
+            .ok_or(anyhow!("Overflow"))?;
+        self.total = self.total.checked_sub(amount).ok_or(anyhow!("Overflow"))?;
+        self.available = available;
+        Ok(())
+    }
+
+    /// Available funds plus what is left of the overdraft
+    fn spendable(&self) -> Decimal {
+        self.available.saturating_add(self.overdraft)
+    }
+
+    /// Part of the overdraft in use, i.e. how much below zero `available` is, up to the overdraft.
+    ///
+    /// No interest is charged on it.
+    pub fn overdrawn(&self) -> Decimal {
+        // Negating a zero balance gives -0, which would be output as such
+        if self.available.is_sign_negative() && !self.available.is_zero() {
+            (-self.available).min(self.overdraft)
+        } else {
+            Decimal::ZERO
+        }
+    }
+
+    /// Whether the account has a negative balance, i.e. it was allowed to dispute spent funds or it
+    /// is using its overdraft
+    pub fn is_negative(&self) -> bool {
+        let negative = |balance: Decimal| balance.is_sign_negative() && !balance.is_zero();
+        negative(self.available) || negative(self.total)
+    }
+
+    /// Amount owed by the client after chargebacks on spent funds, zero if there is none
+    pub fn debt(&self) -> Decimal {
+        // As for `overdrawn`, a -0 total owes nothing
+        if self.total.is_sign_negative() && !self.total.is_zero() {
+            -self.total
+        } else {
+            Decimal::ZERO
+        }
+    }
+
+    /// Check the balances are consistent with each other and with `policy`.
+    ///
+    /// `total = available + held` must always hold, `held` can never be negative, and the other
+    /// balances can be negative only within the overdraft, or without bounds if `policy` allows it.
+    pub fn check_invariants(&self, policy: &AccountPolicy) -> Result<()> {
+        if self.available.checked_add(self.held) != Some(self.total) {
+            return Err(anyhow!("Total does not match available plus held"));
+        }
+        if self.held.is_sign_negative() {
+            return Err(anyhow!("Negative held funds"));
+        }
+        if !policy.allow_negative && self.available < -self.overdraft {
+            return Err(anyhow!("Negative balance beyond overdraft"));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -165,29 +461,215 @@ mod tests {
     // If account is locked is checked only through the `execute` interface
     #[test]
     fn test_account_locked() {
+        let policy = AccountPolicy::default();
         let mut account = Account::new(1);
         account.locked = true;
-        assert!(account.execute(Operation::Deposit, Decimal::ONE).is_err());
+        assert!(account
+            .execute(Operation::Deposit, Decimal::ONE, &policy)
+            .is_err());
         assert_eq!(account.total, Decimal::ZERO);
         assert_eq!(account.available, Decimal::ZERO);
         assert_eq!(account.held, Decimal::ZERO);
         // now unlock it
         account.locked = false;
-        assert!(account.execute(Operation::Deposit, Decimal::ONE).is_ok());
+        assert!(account
+            .execute(Operation::Deposit, Decimal::ONE, &policy)
+            .is_ok());
         // now lock and check balances are untouched
         account.locked = true;
-        assert!(account.execute(Operation::Withdraw, Decimal::ONE).is_err());
+        assert!(account
+            .execute(Operation::Withdraw, Decimal::ONE, &policy)
+            .is_err());
         assert_eq!(account.total, Decimal::ONE);
         assert_eq!(account.available, Decimal::ONE);
         assert_eq!(account.held, Decimal::ZERO);
     }
 
+    // Outstanding disputes can be settled on a locked account, unless the policy says otherwise
+    #[test]
+    fn test_account_locked_policy() {
+        let mut account = Account {
+            id: 1,
+            locked: true,
+            total: Decimal::TWO,
+            available: Decimal::ZERO,
+            held: Decimal::TWO,
+            overdraft: Decimal::ZERO,
+        };
+        let policy = AccountPolicy::default();
+        account
+            .execute(Operation::Resolve, Decimal::ONE, &policy)
+            .unwrap();
+        account
+            .execute(Operation::Chargeback, Decimal::ONE, &policy)
+            .unwrap();
+        assert!(account
+            .execute(Operation::Dispute, Decimal::ONE, &policy)
+            .is_err());
+        assert_eq!(account.total, Decimal::ONE);
+        assert_eq!(account.available, Decimal::ONE);
+        assert_eq!(account.held, Decimal::ZERO);
+
+        // Deny everything
+        let policy = AccountPolicy {
+            locked: LockPolicy::deny_all(),
+            ..Default::default()
+        };
+        assert!(account
+            .execute(Operation::Resolve, Decimal::ZERO, &policy)
+            .is_err());
+
+        // Allow deposits only
+        let mut locked = LockPolicy::deny_all();
+        locked.allow(Operation::Deposit);
+        let policy = AccountPolicy {
+            locked,
+            ..Default::default()
+        };
+        account
+            .execute(Operation::Deposit, Decimal::ONE, &policy)
+            .unwrap();
+        assert!(account
+            .execute(Operation::Withdraw, Decimal::ONE, &policy)
+            .is_err());
+        assert_eq!(account.total, Decimal::TWO);
+    }
+
+    // Reversals undo deposits and withdrawals, and are not allowed on locked accounts by default
+    #[test]
+    fn test_account_reversal() {
+        let policy = AccountPolicy::default();
+        let mut account = Account::new(1);
+        account
+            .execute(Operation::Deposit, Decimal::TEN, &policy)
+            .unwrap();
+        account
+            .execute(Operation::ReverseDeposit, Decimal::TWO, &policy)
+            .unwrap();
+        account
+            .execute(Operation::ReverseWithdrawal, Decimal::ONE, &policy)
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(9, 0));
+        assert_eq!(account.available, Decimal::new(9, 0));
+        assert!(account
+            .execute(Operation::ReverseDeposit, Decimal::TEN, &policy)
+            .is_err());
+
+        account.locked = true;
+        assert!(account
+            .execute(Operation::ReverseWithdrawal, Decimal::ONE, &policy)
+            .is_err());
+        let mut locked = LockPolicy::deny_all();
+        locked.allow(Operation::ReverseDeposit);
+        assert!(locked.allows(Operation::ReverseWithdrawal));
+    }
+
+    // Authorizations hold available funds, then are captured or released without locking the account
+    #[test]
+    fn test_account_authorization() {
+        let policy = AccountPolicy {
+            allow_negative: true,
+            ..Default::default()
+        };
+        let mut account = Account::new(1);
+        account
+            .execute(Operation::Deposit, Decimal::TEN, &policy)
+            .unwrap();
+        account
+            .execute(Operation::Authorize, Decimal::new(6, 0), &policy)
+            .unwrap();
+        // Never more than available, whatever the policy
+        assert!(account
+            .execute(Operation::Authorize, Decimal::new(6, 0), &policy)
+            .is_err());
+        account
+            .execute(Operation::Capture, Decimal::new(4, 0), &policy)
+            .unwrap();
+        account
+            .execute(Operation::Release, Decimal::ONE, &policy)
+            .unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.total, Decimal::new(6, 0));
+        assert_eq!(account.available, Decimal::new(5, 0));
+        assert_eq!(account.held, Decimal::ONE);
+        assert!(account
+            .execute(Operation::Capture, Decimal::TWO, &policy)
+            .is_err());
+
+        // Held funds can still be captured on a locked account
+        account.locked = true;
+        account
+            .execute(Operation::Capture, Decimal::ONE, &policy)
+            .unwrap();
+        assert!(account
+            .execute(Operation::Authorize, Decimal::ONE, &policy)
+            .is_err());
+    }
+
+    // A withdrawal and its fee are checked together, other operations never fail because of their fee
+    #[test]
+    fn test_execute_with_fee() {
+        let policy = AccountPolicy::default();
+        let mut account = Account::new(1);
+        account
+            .execute(Operation::Deposit, Decimal::TEN, &policy)
+            .unwrap();
+        assert!(account
+            .execute_with_fee(
+                Operation::Withdraw,
+                Decimal::new(9, 0),
+                Decimal::TWO,
+                &policy
+            )
+            .is_err());
+        assert_eq!(account.available, Decimal::TEN);
+        let fee = account
+            .execute_with_fee(
+                Operation::Withdraw,
+                Decimal::new(7, 0),
+                Decimal::TWO,
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(fee, Decimal::TWO);
+        assert_eq!(account.total, Decimal::ONE);
+
+        account
+            .execute(Operation::Dispute, Decimal::ONE, &policy)
+            .unwrap();
+        let fee = account
+            .execute_with_fee(Operation::Chargeback, Decimal::ONE, Decimal::TWO, &policy)
+            .unwrap();
+        assert_eq!(fee, Decimal::ZERO);
+        assert!(account.locked);
+        assert_eq!(account.total, Decimal::ZERO);
+
+        // With negative balances allowed, the fee is charged in full
+        account.locked = false;
+        let policy = AccountPolicy {
+            allow_negative: true,
+            ..Default::default()
+        };
+        account
+            .execute(Operation::Dispute, Decimal::ONE, &policy)
+            .unwrap();
+        let fee = account
+            .execute_with_fee(Operation::Chargeback, Decimal::ONE, Decimal::TWO, &policy)
+            .unwrap();
+        assert_eq!(fee, Decimal::TWO);
+        assert_eq!(account.total, Decimal::new(-3, 0));
+    }
+
     // If `amount` is negative is checked only through the `execute` interface
     #[test]
     fn test_negative_amount() {
         let mut account = Account::new(1);
         assert!(account
-            .execute(Operation::Deposit, Decimal::new(-1, 0))
+            .execute(
+                Operation::Deposit,
+                Decimal::new(-1, 0),
+                &AccountPolicy::default()
+            )
             .is_err());
         assert_eq!(account.total, Decimal::ZERO);
         assert_eq!(account.available, Decimal::ZERO);
@@ -221,6 +703,7 @@ mod tests {
             total: Decimal::TWO,
             available: Decimal::TWO,
             held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
         };
         account.withdraw(Decimal::ONE).unwrap();
         assert_eq!(account.total, Decimal::ONE);
@@ -236,6 +719,7 @@ mod tests {
             total: Decimal::ONE,
             available: Decimal::ONE,
             held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
         };
         assert!(account.withdraw(Decimal::TWO).is_err());
         // Check balances are unaffected
@@ -248,7 +732,7 @@ mod tests {
     fn test_dispute_ok() {
         let mut account = Account::new(1);
         account.deposit(Decimal::TWO).unwrap();
-        account.dispute(Decimal::ONE).unwrap();
+        account.dispute(Decimal::ONE, false).unwrap();
         assert_eq!(account.total, Decimal::TWO);
         assert_eq!(account.available, Decimal::ONE);
         assert_eq!(account.held, Decimal::ONE);
@@ -262,9 +746,10 @@ mod tests {
             total: Decimal::TWO,
             available: Decimal::ONE,
             held: Decimal::ONE,
+            overdraft: Decimal::ZERO,
         };
         let expected = account.clone();
-        assert!(account.dispute(Decimal::TWO).is_err());
+        assert!(account.dispute(Decimal::TWO, false).is_err());
         assert_eq!(account, expected);
     }
 
@@ -276,6 +761,7 @@ mod tests {
             total: Decimal::TWO,
             available: Decimal::ONE,
             held: Decimal::ONE,
+            overdraft: Decimal::ZERO,
         };
         account.resolve(Decimal::ONE).unwrap();
         let expected = Account {
@@ -284,6 +770,7 @@ mod tests {
             total: Decimal::TWO,
             available: Decimal::TWO,
             held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
         };
         assert_eq!(account, expected);
     }
@@ -296,6 +783,7 @@ mod tests {
             total: Decimal::TWO,
             available: Decimal::ONE,
             held: Decimal::ONE,
+            overdraft: Decimal::ZERO,
         };
         let expected = account.clone();
         assert!(account.resolve(Decimal::TWO).is_err());
@@ -310,6 +798,7 @@ mod tests {
             total: Decimal::TWO,
             available: Decimal::ONE,
             held: Decimal::ONE,
+            overdraft: Decimal::ZERO,
         };
         account.chargeback(Decimal::ONE).unwrap();
         let expected = Account {
@@ -318,7 +807,167 @@ mod tests {
             total: Decimal::ONE,
             available: Decimal::ONE,
             held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
+        };
+        assert_eq!(account, expected);
+    }
+
+    #[test]
+    fn test_dispute_negative() {
+        let mut account = Account {
+            id: 1,
+            locked: false,
+            total: Decimal::TWO,
+            available: Decimal::ONE,
+            held: Decimal::ONE,
+            overdraft: Decimal::ZERO,
+        };
+        account.dispute(Decimal::TWO, true).unwrap();
+        let expected = Account {
+            id: 1,
+            locked: false,
+            total: Decimal::TWO,
+            available: -Decimal::ONE,
+            held: Decimal::new(3, 0),
+            overdraft: Decimal::ZERO,
+        };
+        assert_eq!(account, expected);
+        assert!(account.is_negative());
+        assert_eq!(account.debt(), Decimal::ZERO);
+    }
+
+    // A chargeback on spent funds leaves a debt behind
+    #[test]
+    fn test_chargeback_negative() {
+        let policy = AccountPolicy {
+            allow_negative: true,
+            ..Default::default()
+        };
+        let mut account = Account::new(1);
+        account
+            .execute(Operation::Deposit, Decimal::TWO, &policy)
+            .unwrap();
+        account
+            .execute(Operation::Withdraw, Decimal::TWO, &policy)
+            .unwrap();
+        account
+            .execute(Operation::Dispute, Decimal::TWO, &policy)
+            .unwrap();
+        // Cannot withdraw into negative
+        assert!(account
+            .execute(Operation::Withdraw, Decimal::ONE, &policy)
+            .is_err());
+        account
+            .execute(Operation::Chargeback, Decimal::TWO, &policy)
+            .unwrap();
+        let expected = Account {
+            id: 1,
+            locked: true,
+            total: -Decimal::TWO,
+            available: -Decimal::TWO,
+            held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
         };
         assert_eq!(account, expected);
+        assert_eq!(account.debt(), Decimal::TWO);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let policy = AccountPolicy::default();
+        let mut account = Account {
+            id: 1,
+            locked: false,
+            total: Decimal::TWO,
+            available: Decimal::ONE,
+            held: Decimal::ONE,
+            overdraft: Decimal::ZERO,
+        };
+        assert!(account.check_invariants(&policy).is_ok());
+
+        account.total = Decimal::ONE;
+        assert!(account.check_invariants(&policy).is_err());
+
+        account.held = -Decimal::ONE;
+        account.available = Decimal::TWO;
+        assert!(account.check_invariants(&policy).is_err());
+
+        // Negative balances depend on policy
+        account.held = Decimal::TWO;
+        account.available = -Decimal::ONE;
+        assert!(account.check_invariants(&policy).is_err());
+        account.overdraft = Decimal::ONE;
+        assert!(account.check_invariants(&policy).is_ok());
+        account.overdraft = Decimal::ZERO;
+        let policy = AccountPolicy {
+            allow_negative: true,
+            ..Default::default()
+        };
+        assert!(account.check_invariants(&policy).is_ok());
+    }
+
+    // Withdrawals and disputes may use the overdraft, and a chargeback leaves the account overdrawn.
+    // Authorizations may not.
+    #[test]
+    fn test_overdraft() {
+        let policy = AccountPolicy::default();
+        let mut account = Account::new(1).with_overdraft(Decimal::TEN);
+        account
+            .execute(Operation::Deposit, Decimal::new(5, 0), &policy)
+            .unwrap();
+        account
+            .execute(Operation::Withdraw, Decimal::new(8, 0), &policy)
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(-3, 0));
+        assert_eq!(account.overdrawn(), Decimal::new(3, 0));
+        assert!(account
+            .execute(Operation::Withdraw, Decimal::new(8, 0), &policy)
+            .is_err());
+
+        // The deposit was spent: disputing it uses the overdraft too
+        account
+            .execute(Operation::Dispute, Decimal::new(5, 0), &policy)
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(-8, 0));
+        assert_eq!(account.held, Decimal::new(5, 0));
+        assert!(account
+            .execute(Operation::Dispute, Decimal::new(3, 0), &policy)
+            .is_err());
+        account
+            .execute(Operation::Chargeback, Decimal::new(5, 0), &policy)
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(-8, 0));
+        assert_eq!(account.overdrawn(), Decimal::new(8, 0));
+        assert!(account.is_negative());
+        assert!(account.check_invariants(&policy).is_ok());
+
+        // Deposits pay the overdraft back first
+        account.locked = false;
+        account
+            .execute(Operation::Deposit, Decimal::TEN, &policy)
+            .unwrap();
+        assert_eq!(account.overdrawn(), Decimal::ZERO);
+        assert_eq!(account.available, Decimal::TWO);
+
+        // Authorizations never use the overdraft
+        assert!(account
+            .execute(Operation::Authorize, Decimal::new(3, 0), &policy)
+            .is_err());
+        account
+            .execute(Operation::Authorize, Decimal::TWO, &policy)
+            .unwrap();
+        assert_eq!(account.available, Decimal::ZERO);
+
+        // Not "-0"
+        let account = Account::new(2).with_overdraft(Decimal::TEN);
+        assert_eq!(account.overdrawn().to_string(), "0");
+        // Nor negative, nor in debt, with -0 balances
+        let account = Account {
+            available: -Decimal::ZERO,
+            total: -Decimal::ZERO,
+            ..Account::new(3)
+        };
+        assert!(!account.is_negative());
+        assert_eq!(account.debt().to_string(), "0");
     }
 }
@@ -0,0 +1,85 @@
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// Why an account got locked
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockReason {
+    Chargeback,
+    OpenDisputes,
+    DisputedAmount,
+}
+
+impl fmt::Display for LockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockReason::Chargeback => f.write_str("chargeback"),
+            LockReason::OpenDisputes => f.write_str("open_disputes"),
+            LockReason::DisputedAmount => f.write_str("disputed_amount"),
+        }
+    }
+}
+
+/// Dispute statistics of a client
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DisputeStats {
+    /// Transactions currently under dispute
+    pub open_disputes: usize,
+    /// Amount currently under dispute
+    pub disputed: Decimal,
+}
+
+/// Thresholds beyond which an account is locked automatically, `None` meaning no threshold.
+///
+/// There is no threshold on chargebacks: a single one already locks the account.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AutoLockPolicy {
+    pub max_open_disputes: Option<usize>,
+    pub max_disputed: Option<Decimal>,
+}
+
+impl AutoLockPolicy {
+    /// Whether any threshold is set
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Why an account with `stats` must be locked, if it must
+    pub fn check(&self, stats: &DisputeStats) -> Option<LockReason> {
+        if self
+            .max_open_disputes
+            .is_some_and(|max| stats.open_disputes > max)
+        {
+            return Some(LockReason::OpenDisputes);
+        }
+        if self.max_disputed.is_some_and(|max| stats.disputed > max) {
+            return Some(LockReason::DisputedAmount);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let policy = AutoLockPolicy {
+            max_open_disputes: Some(2),
+            max_disputed: Some(Decimal::ONE_HUNDRED),
+        };
+        assert!(policy.is_enabled());
+        assert!(!AutoLockPolicy::default().is_enabled());
+
+        let mut stats = DisputeStats {
+            open_disputes: 2,
+            disputed: Decimal::ONE_HUNDRED,
+        };
+        assert_eq!(policy.check(&stats), None);
+        stats.disputed += Decimal::ONE;
+        assert_eq!(policy.check(&stats), Some(LockReason::DisputedAmount));
+        stats.open_disputes = 3;
+        assert_eq!(policy.check(&stats), Some(LockReason::OpenDisputes));
+        assert_eq!(AutoLockPolicy::default().check(&stats), None);
+    }
+}
@@ -0,0 +1,140 @@
+use std::iter::Peekable;
+
+/// Iterator grouping consecutive items of the same batch.
+///
+/// `batch_of` tells the batch id of an item. Items with no batch id are returned alone, unless
+/// `is_unknown` tells their batch cannot be known: such an item is grouped with the batches on both
+/// sides of it, as it may belong to either.
+pub struct Batches<I: Iterator, F, G> {
+    items: Peekable<I>,
+    batch_of: F,
+    is_unknown: G,
+}
+
+impl<I, F> Batches<I, F, fn(&I::Item) -> bool>
+where
+    I: Iterator,
+    F: Fn(&I::Item) -> Option<u32>,
+{
+    pub fn new(items: I, batch_of: F) -> Self {
+        Self {
+            items: items.peekable(),
+            batch_of,
+            is_unknown: |_| false,
+        }
+    }
+}
+
+impl<I, F, G> Batches<I, F, G>
+where
+    I: Iterator,
+    F: Fn(&I::Item) -> Option<u32>,
+    G: Fn(&I::Item) -> bool,
+{
+    /// Tell the items whose batch cannot be known
+    pub fn with_unknown<H: Fn(&I::Item) -> bool>(self, is_unknown: H) -> Batches<I, F, H> {
+        Batches {
+            items: self.items,
+            batch_of: self.batch_of,
+            is_unknown,
+        }
+    }
+}
+
+impl<I, F, G> Iterator for Batches<I, F, G>
+where
+    I: Iterator,
+    F: Fn(&I::Item) -> Option<u32>,
+    G: Fn(&I::Item) -> bool,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.items.next()?;
+        let mut batch = (self.batch_of)(&first);
+        // After an item of unknown batch, the next batch joins in, whatever it is
+        let mut bridge = (self.is_unknown)(&first);
+        let mut items = vec![first];
+        if batch.is_some() || bridge {
+            let (batch_of, is_unknown) = (&self.batch_of, &self.is_unknown);
+            while let Some(item) = self.items.next_if(|item| {
+                is_unknown(item) || batch_of(item).is_some_and(|id| bridge || Some(id) == batch)
+            }) {
+                if is_unknown(&item) {
+                    bridge = true;
+                } else if bridge {
+                    batch = batch_of(&item);
+                    bridge = false;
+                }
+                items.push(item);
+            }
+        }
+        Some(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batches() {
+        let items = [
+            None,
+            Some(1),
+            Some(1),
+            None,
+            None,
+            Some(2),
+            Some(1),
+            Some(1),
+        ];
+        let batches: Vec<_> = Batches::new(items.into_iter(), |item| *item).collect();
+        assert_eq!(
+            batches,
+            vec![
+                vec![None],
+                vec![Some(1), Some(1)],
+                vec![None],
+                vec![None],
+                vec![Some(2)],
+                vec![Some(1), Some(1)],
+            ]
+        );
+        assert_eq!(Batches::new([None; 0].into_iter(), |item| *item).count(), 0);
+    }
+
+    #[test]
+    fn test_unknown() {
+        // Batch 0 stands for an unknown one
+        let items = [
+            Some(1),
+            Some(0),
+            Some(1),
+            None,
+            Some(0),
+            Some(2),
+            Some(3),
+            Some(0),
+            Some(4),
+            None,
+            Some(0),
+            None,
+        ];
+        let batches: Vec<_> = Batches::new(items.into_iter(), |item| item.filter(|id| *id != 0))
+            .with_unknown(|item| *item == Some(0))
+            .collect();
+        assert_eq!(
+            batches,
+            vec![
+                vec![Some(1), Some(0), Some(1)],
+                vec![None],
+                vec![Some(0), Some(2)],
+                vec![Some(3), Some(0), Some(4)],
+                vec![None],
+                vec![Some(0)],
+                vec![None],
+            ]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::time::{Duration, Instant};
+
+/// How often, and how long, one side of a channel had to wait for the other
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WaitStats {
+    /// Chunks sent or received
+    pub chunks: u64,
+    /// Times the side blocked: the channel was full for the sender, or empty for the receiver
+    pub blocked: u64,
+    /// Time spent blocked
+    pub waited: Duration,
+}
+
+/// Create a bounded channel handing items over in chunks of `chunk_size`, with at most `capacity`
+/// chunks in flight. With a capacity of 0, each chunk is handed over directly.
+pub fn chunked<T>(capacity: usize, chunk_size: usize) -> (ChunkSender<T>, ChunkReceiver<T>) {
+    let (tx, rx) = mpsc::sync_channel(capacity);
+    let sender = ChunkSender {
+        tx,
+        chunk: Vec::with_capacity(chunk_size),
+        chunk_size: chunk_size.max(1),
+        stats: WaitStats::default(),
+    };
+    let receiver = ChunkReceiver {
+        rx,
+        stats: WaitStats::default(),
+    };
+    (sender, receiver)
+}
+
+/// Sending half of a chunked channel
+pub struct ChunkSender<T> {
+    tx: SyncSender<Vec<T>>,
+    chunk: Vec<T>,
+    chunk_size: usize,
+    stats: WaitStats,
+}
+
+impl<T> ChunkSender<T> {
+    /// Queue `item`, sending the chunk once it is full
+    pub fn send(&mut self, item: T) -> Result<()> {
+        self.chunk.push(item);
+        if self.chunk.len() >= self.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Send the items queued so far, blocking while the channel is full
+    fn flush(&mut self) -> Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(self.chunk_size));
+        match self.tx.try_send(chunk) {
+            Ok(()) => {}
+            Err(TrySendError::Full(chunk)) => {
+                let start = Instant::now();
+                self.tx.send(chunk).map_err(|_| anyhow!("Receiver gone"))?;
+                self.stats.blocked += 1;
+                self.stats.waited += start.elapsed();
+            }
+            Err(TrySendError::Disconnected(_)) => return Err(anyhow!("Receiver gone")),
+        }
+        self.stats.chunks += 1;
+        Ok(())
+    }
+
+    /// Send the last, partial chunk and close the channel, returning the sender's stats
+    pub fn finish(mut self) -> Result<WaitStats> {
+        self.flush()?;
+        Ok(self.stats)
+    }
+}
+
+/// Receiving half of a chunked channel
+pub struct ChunkReceiver<T> {
+    rx: Receiver<Vec<T>>,
+    stats: WaitStats,
+}
+
+impl<T> ChunkReceiver<T> {
+    /// Next chunk, blocking while the channel is empty. `None` once the sender is gone.
+    pub fn recv(&mut self) -> Option<Vec<T>> {
+        let chunk = match self.rx.try_recv() {
+            Ok(chunk) => chunk,
+            Err(TryRecvError::Empty) => {
+                let start = Instant::now();
+                let chunk = self.rx.recv().ok();
+                self.stats.blocked += 1;
+                self.stats.waited += start.elapsed();
+                chunk?
+            }
+            Err(TryRecvError::Disconnected) => return None,
+        };
+        self.stats.chunks += 1;
+        Some(chunk)
+    }
+
+    /// Iterate over the items, chunk after chunk
+    pub fn iter(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.recv()).flatten()
+    }
+
+    /// The receiver's stats so far
+    pub fn stats(&self) -> WaitStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_chunks() {
+        let (mut tx, mut rx) = chunked(4, 2);
+        for item in 1..=5 {
+            tx.send(item).unwrap();
+        }
+        let stats = tx.finish().unwrap();
+        assert_eq!(stats.chunks, 3);
+        assert_eq!(stats.blocked, 0);
+
+        assert_eq!(rx.recv(), Some(vec![1, 2]));
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(rx.recv(), None);
+        assert_eq!(rx.stats().chunks, 3);
+    }
+
+    #[test]
+    fn test_blocking() {
+        let (mut tx, mut rx) = chunked(1, 1);
+        let sender = thread::spawn(move || {
+            for item in 0..3 {
+                tx.send(item).unwrap();
+            }
+            tx.finish().unwrap()
+        });
+        // The sender fills the channel and waits for the receiver
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        let stats = sender.join().unwrap();
+        assert_eq!(stats.chunks, 3);
+        assert!(stats.blocked >= 1);
+        assert!(stats.waited >= Duration::from_millis(10));
+        // Having drained the channel, the receiver waited for the sender at least once
+        assert!(rx.stats().blocked >= 1);
+    }
+
+    #[test]
+    fn test_receiver_gone() {
+        let (mut tx, rx) = chunked(1, 1);
+        drop(rx);
+        assert!(tx.send(1).is_err());
+    }
+}
@@ -1,5 +1,6 @@
+use crate::deser::Record;
 use anyhow::Result;
-use csv::{Reader, ReaderBuilder, Trim, Writer, WriterBuilder};
+use csv::{ByteRecord, Reader, ReaderBuilder, Trim, Writer, WriterBuilder};
 use std::io::{Read, Write};
 use std::path::Path;
 
@@ -42,17 +43,82 @@ impl<W: Write> CsvWriterBuilder<W> {
     }
 }
 
+/// A row of the input that cannot be read as a `Record`
+#[derive(Debug)]
+pub struct InvalidRow {
+    /// Batch of the row, if at least its `batch` column can be read
+    pub batch: Option<u32>,
+    pub error: csv::Error,
+}
+
+/// A row of the input, read or not
+pub type Row = std::result::Result<Record, InvalidRow>;
+
+/// Batch a row belongs to, whether it can be read or not
+pub fn batch_of(row: &Row) -> Option<u32> {
+    match row {
+        Ok(record) => record.batch,
+        Err(invalid) => invalid.batch,
+    }
+}
+
+/// Whether a row cannot be read and tells no batch. Such a row may belong to the batches on both
+/// sides of it, e.g. if it was cut short before its `batch` column.
+pub fn is_unknown_batch(row: &Row) -> bool {
+    matches!(row, Err(InvalidRow { batch: None, .. }))
+}
+
+/// Read the rows of `rdr` as records, keeping the batch of the rows that cannot be read so that
+/// they can reject their whole batch.
+///
+/// Even a row with the wrong number of fields is read as it is, so its batch can be told.
+pub fn read_rows<R: Read>(rdr: &mut Reader<R>) -> impl Iterator<Item = Row> + '_ {
+    let headers = rdr.byte_headers().ok().cloned();
+    let batch_column = headers
+        .as_ref()
+        .and_then(|headers| headers.iter().position(|name| name == b"batch"));
+    let mut row = ByteRecord::new();
+    std::iter::from_fn(move || {
+        let read = rdr.read_byte_record(&mut row);
+        let batch = || {
+            let batch = row.get(batch_column?)?;
+            std::str::from_utf8(batch).ok()?.trim().parse().ok()
+        };
+        match read {
+            Ok(false) => None,
+            Ok(true) => Some(
+                row.deserialize(headers.as_ref())
+                    .map_err(|error| InvalidRow {
+                        batch: batch(),
+                        error,
+                    }),
+            ),
+            Err(error) => Some(Err(InvalidRow {
+                batch: batch(),
+                error,
+            })),
+        }
+    })
+}
+
 /// Convenient wrapper for creating a proper csv::Reader from a file
 pub fn csv_reader_from_file(file_path: &Path) -> Result<Reader<std::fs::File>> {
     let file = std::fs::File::open(file_path)?;
     Ok(CsvReaderBuilder::new(file).build())
 }
 
+/// Convenient wrapper for creating a proper csv::Writer to a file
+pub fn csv_writer_to_file(file_path: &Path) -> Result<Writer<std::fs::File>> {
+    let file = std::fs::File::create(file_path)?;
+    Ok(CsvWriterBuilder::new(file).build())
+}
+
 // The whole test suite tests csv together with `Record` and `OutRecord` deser.
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::deser::Record;
+    use crate::account::Account;
+    use crate::deser::{OutRecord, Record, RejectRecord};
     use itertools::Itertools;
     use rust_decimal::Decimal;
     use std::io::{Cursor, Write};
@@ -68,18 +134,22 @@ mod tests {
 
         let mut rdr = csv_reader_from_file(temp_file.path()).unwrap();
 
-        let expected = vec![
+        let expected = [
             Record {
                 command: "deposit".to_string(),
                 client: 1,
                 tx: 1,
                 amount: Some(Decimal::new(133, 2)), // 1.33
+                batch: None,
+                timestamp: None,
             },
             Record {
                 command: "dispute".to_string(),
                 client: 1,
                 tx: 1,
                 amount: None,
+                batch: None,
+                timestamp: None,
             },
         ];
         for (entry, expected_record) in rdr.deserialize().zip_eq(expected.iter()) {
@@ -88,6 +158,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_csv_read_timestamps() {
+        let data = "type,client,tx,amount,timestamp\n\
+                    deposit,1,1,1.0,2024-01-01T10:00:00+01:00\n\
+                    deposit,1,2,1.0,1704099600\n\
+                    deposit,1,3,1.0,\n\
+                    deposit,1,4,1.0,yesterday\n";
+        let mut rdr = CsvReaderBuilder::new(Cursor::new(data)).build();
+        let records: Vec<_> = rdr.deserialize::<Record>().collect();
+        let timestamp = records[0].as_ref().unwrap().timestamp;
+        assert_eq!(timestamp.unwrap().to_string(), "2024-01-01T09:00:00Z");
+        assert_eq!(records[1].as_ref().unwrap().timestamp, timestamp);
+        assert_eq!(records[2].as_ref().unwrap().timestamp, None);
+        assert!(records[3].is_err());
+    }
+
+    #[test]
+    fn test_csv_read_rows() {
+        let data = "type,client,tx,amount,batch\n\
+                    deposit,1,1,1.0,7\n\
+                    deposit,x,2,1.0,7\n\
+                    deposit,1,3,1.0,7,extra\n\
+                    deposit,x,4,1.0,\n\
+                    deposit,1\n\
+                    deposit,1,6,1.0,7\n";
+        let mut rdr = CsvReaderBuilder::new(Cursor::new(data)).build();
+        let rows: Vec<_> = read_rows(&mut rdr).collect();
+        assert_eq!(rows[0].as_ref().unwrap().tx, 1);
+        assert_eq!(rows[5].as_ref().unwrap().tx, 6);
+        assert!(rows[1..5].iter().all(|row| row.is_err()));
+        // The batch of a row with the wrong number of fields is still read, as long as it has one
+        assert_eq!(
+            rows.iter().map(batch_of).collect_vec(),
+            [Some(7), Some(7), Some(7), None, None, Some(7)]
+        );
+        assert_eq!(
+            rows.iter().map(is_unknown_batch).collect_vec(),
+            [false, false, false, true, true, false]
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_csv_read_negative_numbers() {
@@ -107,9 +218,18 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_csv_read_huge_client_id() {
-        let data = "type,client,tx,amount\ndeposit, 100000, 2, 1.2";
+        let data = "type,client,tx,amount\ndeposit, 100000, 5000000000, 1.2";
+        let mut rdr = CsvReaderBuilder::new(Cursor::new(data)).build();
+        let record: Record = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(record.client, 100_000);
+        assert_eq!(record.tx, 5_000_000_000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_csv_read_negative_client_id() {
+        let data = "type,client,tx,amount\ndeposit, -1, 2, 1.2";
         let mut rdr = CsvReaderBuilder::new(Cursor::new(data)).build();
         let _: Record = rdr.deserialize().next().unwrap().unwrap();
     }
@@ -132,6 +252,81 @@ mod tests {
 
     #[test]
     fn test_csv_write_ok() {
-        // ToDo
+        let account = Account {
+            id: 1,
+            locked: false,
+            total: Decimal::new(15, 1),
+            available: Decimal::ONE,
+            held: Decimal::new(5, 1),
+            overdraft: Decimal::ZERO,
+        };
+        let mut wtr = CsvWriterBuilder::new(vec![]).build();
+        wtr.serialize(OutRecord::from(&account)).unwrap();
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            "client,available,held,total,locked\n1,1,0.5,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_write_negative_flag() {
+        let account = Account {
+            id: 1,
+            locked: true,
+            total: -Decimal::ONE,
+            available: -Decimal::ONE,
+            held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
+        };
+        let mut out_record = OutRecord::from(&account);
+        out_record.negative = Some(account.is_negative());
+        out_record.debt = Some(account.debt());
+        let mut wtr = CsvWriterBuilder::new(vec![]).build();
+        wtr.serialize(out_record).unwrap();
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            "client,available,held,total,locked,negative,debt\n1,-1,0,-1,true,true,1\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_write_overdraft() {
+        let account = Account::new(1).with_overdraft(Decimal::TEN);
+        let mut out_record = OutRecord::from(&Account {
+            available: -Decimal::TWO,
+            total: -Decimal::TWO,
+            ..account
+        });
+        out_record.overdraft = Some(Decimal::TEN);
+        out_record.overdrawn = Some(Decimal::TWO);
+        let mut wtr = CsvWriterBuilder::new(vec![]).build();
+        wtr.serialize(out_record).unwrap();
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            "client,available,held,total,locked,overdraft,overdrawn\n1,-2,0,-2,false,10,2\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_write_reject() {
+        let withdrawal = Record::new("withdrawal", 1, 2, Some(Decimal::TEN));
+        let dispute = Record::new("dispute", 1, 3, None);
+        let mut wtr = CsvWriterBuilder::new(vec![]).build();
+        let error = "Window withdrawal limit exceeded".to_string();
+        wtr.serialize(RejectRecord::new(4, &withdrawal, error))
+            .unwrap();
+        let error = "Transaction not found".to_string();
+        wtr.serialize(RejectRecord::new(5, &dispute, error))
+            .unwrap();
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            "line,type,client,tx,amount,error\n\
+             4,withdrawal,1,2,10,Window withdrawal limit exceeded\n\
+             5,dispute,1,3,,Transaction not found\n"
+        );
     }
 }
@@ -1,4 +1,6 @@
 use crate::account::Account;
+use crate::ids::{ClientId, TxId};
+use crate::timestamp::Timestamp;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
@@ -17,20 +19,72 @@ where
 pub struct Record {
     #[serde(rename = "type")]
     pub command: String,
-    pub client: u16,
-    pub tx: u32,
+    pub client: ClientId,
+    pub tx: TxId,
     #[serde(deserialize_with = "deserialize_opt_decimal_with_precision")]
     pub amount: Option<Decimal>,
+    /// Consecutive records with the same batch id are applied all-or-nothing. The column is optional.
+    #[serde(default)]
+    pub batch: Option<u32>,
+    /// When the transaction happened, as RFC 3339 or seconds since the Unix epoch. The column is optional.
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+#[cfg(test)]
+impl Record {
+    /// Build a record as read from the input, out of any batch and without timestamp
+    pub fn new(command: &str, client: ClientId, tx: TxId, amount: Option<Decimal>) -> Self {
+        Self {
+            command: command.to_string(),
+            client,
+            tx,
+            amount,
+            batch: None,
+            timestamp: None,
+        }
+    }
+
+    /// Put the record in `batch`
+    pub fn in_batch(mut self, batch: u32) -> Self {
+        self.batch = Some(batch);
+        self
+    }
+
+    /// Date the record at `timestamp`
+    pub fn at(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
 }
 
 /// This struct represent a CSV record for the output file
-#[derive(Serialize)]
+#[derive(Debug, Serialize)]
 pub struct OutRecord {
-    pub client: u16,
+    pub client: ClientId,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    /// Set only when negative balances are allowed, so the default output format is unchanged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debt: Option<Decimal>,
+    /// Set only when some client has an overdraft: the approved overdraft and how much of it is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overdraft: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overdrawn: Option<Decimal>,
+    /// Set only when interest is configured: the interest credited so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interest: Option<Decimal>,
+    /// Set only when the input has timestamps: when the account was last changed, empty if unknown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<String>,
+    /// Set only when auto-lock is configured: why the account got locked, empty if it is not
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<String>,
 }
 
 impl From<&Account> for OutRecord {
@@ -41,6 +95,62 @@ impl From<&Account> for OutRecord {
             held: value.held,
             total: value.total,
             locked: value.locked,
+            negative: None,
+            debt: None,
+            overdraft: None,
+            overdrawn: None,
+            interest: None,
+            last_activity: None,
+            lock_reason: None,
+        }
+    }
+}
+
+/// This struct represent a CSV record for the output of a replay: an account at a point of the input
+#[derive(Serialize)]
+pub struct ReplayOutRecord {
+    pub at: String,
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl ReplayOutRecord {
+    pub fn new(at: String, account: &Account) -> Self {
+        Self {
+            at,
+            client: account.id,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        }
+    }
+}
+
+/// This struct represent a CSV record for the rejects file: a rejected record and why
+#[derive(Debug, Serialize)]
+pub struct RejectRecord<'a> {
+    pub line: usize,
+    #[serde(rename = "type")]
+    pub command: &'a str,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Option<Decimal>,
+    pub error: String,
+}
+
+impl<'a> RejectRecord<'a> {
+    pub fn new(line: usize, record: &'a Record, error: String) -> Self {
+        Self {
+            line,
+            command: &record.command,
+            client: record.client,
+            tx: record.tx,
+            amount: record.amount,
+            error,
         }
     }
 }
@@ -0,0 +1,190 @@
+use crate::deser::{OutRecord, Record};
+use crate::engine::{BatchError, Engine};
+use crate::ids::{ClientId, TxId};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Outcome of the records of a given type
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct TypeCount {
+    pub accepted: usize,
+    pub rejected: usize,
+}
+
+/// A record that would be rejected
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Rejection {
+    pub line: usize,
+    #[serde(rename = "type")]
+    pub command: String,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub reason: String,
+}
+
+/// Report of a dry run: what would be rejected and what the final balances would be.
+///
+/// Lines are numbered from 1, header excluded. Rows that cannot even be read are counted under the
+/// `invalid` type.
+#[derive(Debug, Default, Serialize)]
+pub struct DryRunReport {
+    pub types: BTreeMap<String, TypeCount>,
+    pub reasons: BTreeMap<String, usize>,
+    pub rejections: Vec<Rejection>,
+    pub invalid: Vec<usize>,
+    pub accounts: Vec<OutRecord>,
+}
+
+impl DryRunReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for the outcome of processing `record`, found at `line`
+    pub fn record(&mut self, line: usize, record: &Record, result: &anyhow::Result<()>) {
+        match result {
+            Ok(()) => self.accept(record),
+            Err(err) => self.reject(line, record, err.root_cause().to_string()),
+        }
+    }
+
+    /// Account for the outcome of processing a batch of `records`, found at `lines`.
+    ///
+    /// If the batch was rolled back, all its records are rejected, not only the culprit.
+    pub fn record_batch(
+        &mut self,
+        lines: &[usize],
+        records: &[Record],
+        result: &Result<(), BatchError>,
+    ) {
+        for (index, (line, record)) in lines.iter().zip(records).enumerate() {
+            match result {
+                Ok(()) => self.accept(record),
+                Err(err) if err.index == index => {
+                    self.reject(*line, record, err.source.root_cause().to_string())
+                }
+                Err(_) => self.rolled_back(*line, record),
+            }
+        }
+    }
+
+    /// Account for `record`, found at `line`, rolled back with its batch
+    pub fn rolled_back(&mut self, line: usize, record: &Record) {
+        self.reject(line, record, "Batch rolled back".to_string());
+    }
+
+    fn accept(&mut self, record: &Record) {
+        self.types
+            .entry(record.command.clone())
+            .or_default()
+            .accepted += 1;
+    }
+
+    fn reject(&mut self, line: usize, record: &Record, reason: String) {
+        self.types
+            .entry(record.command.clone())
+            .or_default()
+            .rejected += 1;
+        *self.reasons.entry(reason.clone()).or_default() += 1;
+        self.rejections.push(Rejection {
+            line,
+            command: record.command.clone(),
+            client: record.client,
+            tx: record.tx,
+            reason,
+        });
+    }
+
+    /// Account for a row at `line` that could not be read
+    pub fn invalid(&mut self, line: usize) {
+        self.types
+            .entry("invalid".to_string())
+            .or_default()
+            .rejected += 1;
+        self.invalid.push(line);
+    }
+
+    /// Add the final balances of `engine`
+    pub fn with_accounts(mut self, engine: &Engine) -> Self {
+        let mut accounts: Vec<_> = engine.get_accounts().values().collect();
+        accounts.sort_by_key(|account| account.id);
+        self.accounts = accounts.into_iter().map(OutRecord::from).collect();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_dry_run_report() {
+        let mut engine = Engine::new();
+        let mut report = DryRunReport::new();
+        let records = [
+            ("deposit", 1, Some(Decimal::TEN)),
+            ("withdrawal", 2, Some(Decimal::ONE_HUNDRED)),
+            ("withdrawal", 3, Some(Decimal::ONE_HUNDRED)),
+            ("dispute", 4, None),
+            ("withdrawal", 5, Some(Decimal::ONE)),
+        ];
+        for (line, (command, tx, amount)) in records.into_iter().enumerate() {
+            let record = Record {
+                command: command.to_string(),
+                client: 1,
+                tx,
+                amount,
+                batch: None,
+                timestamp: None,
+            };
+            let result = engine.process(&record);
+            report.record(line + 1, &record, &result);
+        }
+        report.invalid(6);
+        let report = report.with_accounts(&engine);
+
+        assert_eq!(
+            report.types["withdrawal"],
+            TypeCount {
+                accepted: 1,
+                rejected: 2
+            }
+        );
+        assert_eq!(report.types["deposit"].accepted, 1);
+        assert_eq!(report.types["dispute"].rejected, 1);
+        assert_eq!(report.types["invalid"].rejected, 1);
+        assert_eq!(report.reasons["Insufficient funds"], 2);
+        assert_eq!(report.reasons["Transaction not found"], 1);
+        assert_eq!(report.rejections.len(), 3);
+        assert_eq!(report.rejections[2].line, 4);
+        assert_eq!(report.invalid, vec![6]);
+        assert_eq!(report.accounts.len(), 1);
+        assert_eq!(report.accounts[0].total, Decimal::new(9, 0));
+    }
+
+    #[test]
+    fn test_dry_run_report_batch() {
+        let mut engine = Engine::new();
+        let mut report = DryRunReport::new();
+        let records: Vec<_> = [
+            ("deposit", Decimal::TEN),
+            ("withdrawal", Decimal::ONE_HUNDRED),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(tx, (command, amount))| {
+            Record::new(command, 1, tx as TxId, Some(amount)).in_batch(1)
+        })
+        .collect();
+        let result = engine.process_batch(&records);
+        report.record_batch(&[2, 3], &records, &result);
+
+        assert_eq!(report.types["deposit"].rejected, 1);
+        assert_eq!(report.types["withdrawal"].rejected, 1);
+        assert_eq!(report.reasons["Batch rolled back"], 1);
+        assert_eq!(report.reasons["Insufficient funds"], 1);
+        assert_eq!(report.rejections[0].line, 2);
+        assert!(engine.get_accounts().is_empty());
+    }
+}
@@ -1,95 +1,576 @@
-use crate::account::{Account, Operation};
+use crate::account::{Account, AccountPolicy, Operation};
+use crate::auto_lock::{AutoLockPolicy, DisputeStats, LockReason};
+
 use crate::deser::Record;
-use anyhow::{anyhow, Result};
+use crate::fees::FeeSchedule;
+use crate::history::History;
+use crate::ids::{ClientId, TxId};
+use crate::interest::InterestPolicy;
+use crate::ledger::Journal;
+use crate::limits::Limits;
+use crate::observer::{DisputeState, EngineObserver, Observers};
+
+use crate::reconcile::Flows;
+use crate::risk::{Alert, Risk};
+
+use crate::timestamp::Timestamp;
+use crate::window::{Moment, Span};
+
+use anyhow::{anyhow, Context, Result};
 use rust_decimal::Decimal;
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+/// Kind of a recorded transaction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TxKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// A recorded transaction.
+///
+/// One is kept per deposit and withdrawal, so it only holds what every transaction needs: amounts
+/// charged back are kept apart, as few transactions ever get one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct TxRecord {
+    client: ClientId,
+    kind: TxKind,
+    amount: Decimal,
+    timestamp: Option<Timestamp>,
+    /// Input line of the reversal referencing this transaction, if one was applied
+    reversed_by: Option<u64>,
+}
+
+/// An open authorization hold, tracked apart from disputes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Authorization {
+    client: ClientId,
+    /// Amount still held, i.e. neither captured nor released yet
+    amount: Decimal,
+    /// When the authorization is released, if it expires
+    expires: Option<Deadline>,
+}
+
+/// When an authorization expires. Time deadlines sort first.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Deadline {
+    /// Once a record is timestamped later than this
+    Time(Timestamp),
+    /// Once the client has had more records than this
+    Records(ClientId, u64),
+}
+
+/// A batch was rolled back because one of its records was rejected
+#[derive(Debug)]
+pub struct BatchError {
+    /// Position of the rejected record in the batch
+    pub index: usize,
+    pub source: anyhow::Error,
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Batch rolled back, record {} rejected: {:#}",
+            self.index + 1,
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+/// An invariant found violated in paranoid mode, right after processing a record.
+///
+/// Violations are reported apart from the outcome of the record, which stands.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Violation {
+    pub tx: TxId,
+    pub client: ClientId,
+    pub error: String,
+}
 
 /// This is the Transaction Engine struct.
 ///
 /// This object contains all the transactions logic and can be run in its own thread.
+/// It can be cloned to take a snapshot of its state.
+#[derive(Clone)]
 pub struct Engine {
-    accounts: HashMap<u16, Account>,
-    tx_record: HashMap<u32, (u16, Decimal)>, // it seems only deposits can be disputed, so we just need amount and client_id
-    dispute_record: HashSet<u32>,            // Check if a transaction is under dispute
+    accounts: HashMap<ClientId, Account>,
+    tx_record: HashMap<TxId, TxRecord>, // Deposits and withdrawals, which can be disputed or reversed
+    dispute_record: HashMap<TxId, Decimal>, // Amount currently under dispute for a transaction
+    charged_back: HashMap<TxId, Decimal>, // Amount charged back for a transaction, if any
+    dispute_stats: HashMap<ClientId, DisputeStats>, // Per-client dispute statistics
+    auto_lock: AutoLockPolicy,          // Thresholds beyond which accounts are locked automatically
+    lock_reasons: HashMap<ClientId, LockReason>, // Why each locked account got locked
+    allow_unlock: bool,                 // Whether `unlock` records are applied
+
+    policy: AccountPolicy,
+    limits: Limits,                   // Per-account compliance limits
+    fees: FeeSchedule,                // Fees charged per operation
+    interest: Option<InterestPolicy>, // Interest credited on accruals, if any
+    risk: Risk,                       // Fraud/risk rules run before applying a record
+    observers: Observers,             // Hooks called as records are processed
+
+    paranoid: bool,             // Audit the client's account after every operation
+    violations: Vec<Violation>, // Found by the audits above, until taken
+    flows: HashMap<ClientId, Flows>, // Money that entered or left each client's account
+    journal: Option<Journal>,   // Double-entry journal of every operation, if enabled
+    history: Option<History>,   // Per-client history of applied operations, if enabled
+    authorizations: HashMap<TxId, Authorization>, // Open authorization holds
+    auth_expiry: Option<Span>,  // How long an authorization stays open, if it expires
+    expiries: BTreeSet<(Deadline, TxId)>, // Authorizations by expiry
+    line: u64,                  // Input line of the latest record processed, see `skip`
+    client_clocks: HashMap<ClientId, u64>, // Records of each client processed so far, rolled back or not
+    clock_skew: u64, // Seconds a timestamp may be earlier than the latest one, or later than `now`
+    now: Option<Timestamp>, // Current time future-dated records are checked against, if any
+    latest: Option<Timestamp>, // Latest timestamp of an applied record
+    timestamp: Option<Timestamp>, // Timestamp of the record being processed
+    last_activity: HashMap<ClientId, Timestamp>, // Timestamp of the latest operation on each account
 }
 
 impl Engine {
+    /// Create an Engine with the default policy
+    #[allow(dead_code)]
     pub fn new() -> Self {
+        Self::with_policy(AccountPolicy::default())
+    }
+
+    /// Create an Engine whose accounts follow `policy`
+    pub fn with_policy(policy: AccountPolicy) -> Self {
         Self {
             accounts: HashMap::new(),
             tx_record: HashMap::new(),
-            dispute_record: HashSet::new(),
+            dispute_record: HashMap::new(),
+            charged_back: HashMap::new(),
+            dispute_stats: HashMap::new(),
+            auto_lock: AutoLockPolicy::default(),
+            lock_reasons: HashMap::new(),
+            allow_unlock: false,
+            policy,
+            limits: Limits::default(),
+            fees: FeeSchedule::default(),
+            interest: None,
+            risk: Risk::default(),
+            observers: Observers::default(),
+
+            paranoid: false,
+            violations: Vec::new(),
+            flows: HashMap::new(),
+            journal: None,
+            history: None,
+            authorizations: HashMap::new(),
+            auth_expiry: None,
+            expiries: BTreeSet::new(),
+            line: 0,
+            client_clocks: HashMap::new(),
+            clock_skew: 0,
+            now: None,
+            latest: None,
+            timestamp: None,
+            last_activity: HashMap::new(),
         }
     }
 
+    /// Enforce per-account compliance `limits` (see `Limits`)
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Charge fees following `fees` (see `FeeSchedule`)
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    /// Credit interest following `interest` on accruals, see `accrue`
+    pub fn with_interest(mut self, interest: Option<InterestPolicy>) -> Self {
+        self.interest = interest;
+        self
+    }
+
+    /// Lock accounts whose dispute statistics go beyond the thresholds of `auto_lock`
+    pub fn with_auto_lock(mut self, auto_lock: AutoLockPolicy) -> Self {
+        self.auto_lock = auto_lock;
+        self
+    }
+
+    /// Enable or disable `unlock` records, which unlock accounts not locked by a chargeback
+    pub fn with_unlock(mut self, allow_unlock: bool) -> Self {
+        self.allow_unlock = allow_unlock;
+        self
+    }
+
+    /// Run `risk` rules on every record before applying it (see `Risk`)
+    pub fn with_risk(mut self, risk: Risk) -> Self {
+        self.risk = risk;
+        self
+    }
+
+    /// Call `observer` as records are processed (see `EngineObserver`), after the observers added before
+    pub fn with_observer(mut self, observer: Arc<dyn EngineObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Enable or disable the audit of the client's account after every operation (see `audit_client`)
+    pub fn with_paranoid(mut self, paranoid: bool) -> Self {
+        self.paranoid = paranoid;
+        self
+    }
+
+    /// Enable or disable the double-entry journal, posting entries for every operation
+    pub fn with_journal(mut self, enabled: bool) -> Self {
+        self.journal = enabled.then(Journal::new);
+        self
+    }
+
+    /// Enable or disable the per-client history of applied operations, with resulting balances
+    pub fn with_history(mut self, enabled: bool) -> Self {
+        self.history = enabled.then(History::new);
+        self
+    }
+
+    /// Release authorizations still open after `span`, i.e. that many more records of the client or
+    /// that much time, or never if `None`
+    pub fn with_auth_expiry(mut self, span: Option<Span>) -> Self {
+        self.auth_expiry = span;
+        self
+    }
+
+    /// Accept timestamps up to `secs` seconds earlier than the latest one applied, or later than `now`
+    pub fn with_clock_skew(mut self, secs: u64) -> Self {
+        self.clock_skew = secs;
+        self
+    }
+
+    /// Reject records dated after `now` (give or take the clock skew), or none if `None`
+    pub fn with_now(mut self, now: Option<Timestamp>) -> Self {
+        self.now = now;
+        self
+    }
+
     /// Executes instructions contained in a Record (command)
+    ///
+    /// Timestamped records are checked against the clock skew first, see `check_timestamp`, then
+    /// risk rules are run on the record, which they may reject.
+    /// Stale authorizations are released before applying it, see `with_auth_expiry`.
+    /// In paranoid mode, the client's account is audited afterwards, even if the record was
+    /// rejected, and any violation is kept apart from the outcome of the record, see
+    /// `take_violations`.
     pub fn process(&mut self, record: &Record) -> Result<()> {
+        self.line += 1;
+        *self.client_clocks.entry(record.client).or_default() += 1;
+        self.timestamp = record.timestamp;
+        let result = self.admit(record).and_then(|()| {
+            self.expire_authorizations(record.client);
+            self.apply(record)
+        });
+        match &result {
+            Ok(()) => {
+                self.latest = self.latest.max(record.timestamp);
+                let now = self.moment(record.client);
+                self.risk.applied(record, now);
+            }
+            Err(err) => {
+                self.observers.on_rejection(record, err);
+            }
+        }
+        if self.paranoid {
+            if let Err(err) = self.audit_client(record.client) {
+                self.violations.push(Violation {
+                    tx: record.tx,
+                    client: record.client,
+                    error: format!("{:#}", err),
+                });
+            }
+        }
+        result
+    }
+
+    /// Count `rows` of the input that are not processed, e.g. as they cannot be read.
+    ///
+    /// Records are numbered as lines of the input, from 1, as long as every row not given to
+    /// `process` or `process_batch` is counted here.
+    pub fn skip(&mut self, rows: u64) {
+        self.line += rows;
+    }
+
+    /// Violations found in paranoid mode since they were last taken, in order.
+    ///
+    /// They outlive the rollback of the batch they were found in.
+    pub fn take_violations(&mut self) -> Vec<Violation> {
+        std::mem::take(&mut self.violations)
+    }
+
+    /// Executes all the records of a batch, or none of them.
+    ///
+    /// If a record is rejected, the engine is rolled back to its state before the batch. Observers
+    /// only get the events of the batch once it commits, see `Observers`.
+    /// The rollback relies on a snapshot of the whole engine, so it gets expensive with a big state and
+    /// many small batches.
+    pub fn process_batch(&mut self, records: &[Record]) -> Result<(), BatchError> {
+        let snapshot = self.clone();
+        self.observers.hold();
+        for (index, record) in records.iter().enumerate() {
+            if let Err(source) = self.process(record) {
+                let (line, client_clocks, violations) = (
+                    self.line,
+                    std::mem::take(&mut self.client_clocks),
+                    std::mem::take(&mut self.violations),
+                );
+                *self = snapshot;
+                self.line = line;
+                self.client_clocks = client_clocks;
+                self.violations = violations;
+                return Err(BatchError { index, source });
+            }
+        }
+        self.observers.release();
+        Ok(())
+    }
+
+    /// Check a record can be applied at all, looking at its timestamp and running risk rules.
+    ///
+    /// The house account only earns fees: records targeting it are rejected, except for control
+    /// records, whose client is ignored.
+    fn admit(&mut self, record: &Record) -> Result<()> {
+        if self.fees.is_house(record.client) && record.command != "accrue" {
+            return Err(anyhow!("Account is the house account"));
+        }
+        self.check_timestamp(record.timestamp)?;
+        let now = self.moment(record.client);
+        self.risk
+            .check(record, self.accounts.get(&record.client), now)
+    }
+
+    /// The current moment of a client: its records so far, and the timestamp of the record being
+    /// processed, or of the latest one applied if it has none
+    fn moment(&self, client_id: ClientId) -> Moment {
+        Moment {
+            records: self.client_clocks.get(&client_id).copied().unwrap_or(0),
+            timestamp: self.timestamp.or(self.latest),
+        }
+    }
+
+    /// Check a record's timestamp is neither out of order, i.e. earlier than the latest one applied,
+    /// nor in the future, both give or take the clock skew. Records without a timestamp always pass.
+    fn check_timestamp(&self, timestamp: Option<Timestamp>) -> Result<()> {
+        let Some(timestamp) = timestamp else {
+            return Ok(());
+        };
+        if self
+            .latest
+            .is_some_and(|latest| timestamp.saturating_add_secs(self.clock_skew) < latest)
+        {
+            return Err(anyhow!("Timestamp out of order"));
+        }
+        if self
+            .now
+            .is_some_and(|now| timestamp > now.saturating_add_secs(self.clock_skew))
+        {
+            return Err(anyhow!("Timestamp in the future"));
+        }
+        Ok(())
+    }
+
+    /// Apply the instructions contained in a Record (command)
+    fn apply(&mut self, record: &Record) -> Result<()> {
         match record.command.as_str() {
             "deposit" => {
                 let amount = record.amount.ok_or_else(|| anyhow!("Missing amount"))?;
-                let account = self.get_account(record.client);
-                account.execute(Operation::Deposit, amount)?;
-                self.register_transaction(record.tx, record.client, amount);
+                self.execute(record.client, record.tx, Operation::Deposit, amount)?;
+                self.register_transaction(record.tx, record.client, TxKind::Deposit, amount);
             }
             "withdrawal" => {
                 let amount = record.amount.ok_or_else(|| anyhow!("Missing amount"))?;
-                let account = self.get_account(record.client);
-                account.execute(Operation::Withdraw, amount)?;
-                // We do not record withdrawals
+                self.execute(record.client, record.tx, Operation::Withdraw, amount)?;
+                self.register_transaction(record.tx, record.client, TxKind::Withdrawal, amount);
             }
             "dispute" => {
-                // Check if the transaction has not been disputed already
-                if self.dispute_record.contains(&record.tx) {
+                // Check transaction exists and belongs to the right client
+                let tx_record = self.get_transaction(record)?;
+                // It seems only deposits can be disputed
+                if tx_record.kind != TxKind::Deposit {
+                    return Err(anyhow!("Only deposits can be disputed"));
+                }
+                if tx_record.reversed_by.is_some() {
+                    return Err(anyhow!("Transaction reversed"));
+                }
+                let open = self.disputed_amount(record.tx);
+
+                // Partial disputes are allowed, up to what has not been disputed or charged back yet
+                let undisputed = tx_record.amount - open - self.charged_back_amount(record.tx);
+                if undisputed.is_zero() && !open.is_zero() {
                     return Err(anyhow!("Transaction already under dispute"));
                 }
-                // Check transaction exists and belongs to the right client
-                let (client_id, amount) = *self
-                    .tx_record
-                    .get(&record.tx)
-                    .ok_or_else(|| anyhow!("Transaction not found"))?;
-                if client_id != record.client {
-                    return Err(anyhow!("Transaction does not belong to client"));
+                let amount = record.amount.unwrap_or(undisputed);
+                if amount.is_zero() {
+                    return Err(anyhow!("Amount must be positive"));
+                }
+                if amount > undisputed {
+                    return Err(anyhow!("Amount exceeds undisputed amount"));
                 }
 
-                let account = self.get_account(record.client);
-                account.execute(Operation::Dispute, amount)?;
-                self.dispute_record.insert(record.tx);
+                self.execute(record.client, record.tx, Operation::Dispute, amount)?;
+                self.dispute_record.insert(record.tx, open + amount);
+                self.notify_dispute_change(record.client, record.tx, open, open + amount);
+                let stats = self.dispute_stats.entry(record.client).or_default();
+                stats.open_disputes += usize::from(open.is_zero());
+                stats.disputed += amount;
+                self.apply_auto_lock(record.client);
             }
             "resolve" => {
                 // Check if tx under dispute
-                if !self.dispute_record.contains(&record.tx) {
+                let open = self.disputed_amount(record.tx);
+                if open.is_zero() {
                     return Err(anyhow!("Transaction not under dispute"));
                 }
                 // Get transaction details, if any, and if the client is the correct one
-                let (client_id, amount) = *self
-                    .tx_record
-                    .get(&record.tx)
-                    .ok_or_else(|| anyhow!("Transaction not found"))?;
-                if client_id != record.client {
-                    return Err(anyhow!("Transaction does not belong to client"));
+                self.get_transaction(record)?;
+                let amount = record.amount.unwrap_or(open);
+                if amount.is_zero() {
+                    return Err(anyhow!("Amount must be positive"));
+                }
+                if amount > open {
+                    return Err(anyhow!("Amount exceeds disputed amount"));
                 }
 
-                let account = self.get_account(record.client);
-                account.execute(Operation::Resolve, amount)?;
-                self.dispute_record.remove(&record.tx);
+                self.execute(record.client, record.tx, Operation::Resolve, amount)?;
+                self.settle_dispute(record.client, record.tx, open, amount);
             }
             "chargeback" => {
                 // Check if tx under dispute
-                if !self.dispute_record.contains(&record.tx) {
+                let open = self.disputed_amount(record.tx);
+                if open.is_zero() {
                     return Err(anyhow!("Transaction not under dispute"));
                 }
                 // Get transaction details, if any, and if the client is the correct one
-                let (client_id, amount) = *self
-                    .tx_record
+                self.get_transaction(record)?;
+                let amount = record.amount.unwrap_or(open);
+                if amount.is_zero() {
+                    return Err(anyhow!("Amount must be positive"));
+                }
+                if amount > open {
+                    return Err(anyhow!("Amount exceeds disputed amount"));
+                }
+
+                self.execute(record.client, record.tx, Operation::Chargeback, amount)?;
+                self.settle_dispute(record.client, record.tx, open, amount);
+                *self.charged_back.entry(record.tx).or_default() += amount;
+            }
+            "reversal" => {
+                // Undo the balance effect of a transaction entered in error. This is not a dispute:
+                // nothing is held and the account is not locked.
+                let tx_record = self.get_transaction(record)?;
+                if tx_record.reversed_by.is_some() {
+                    return Err(anyhow!("Transaction already reversed"));
+                }
+                if !self.disputed_amount(record.tx).is_zero() {
+                    return Err(anyhow!("Transaction under dispute"));
+                }
+                if self.charged_back.contains_key(&record.tx) {
+                    return Err(anyhow!("Transaction charged back"));
+                }
+                let operation = match tx_record.kind {
+                    TxKind::Deposit => Operation::ReverseDeposit,
+                    TxKind::Withdrawal => Operation::ReverseWithdrawal,
+                };
+
+                self.execute(record.client, record.tx, operation, tx_record.amount)?;
+                let line = self.line;
+                self.update_transaction(record.tx, |tx_record| tx_record.reversed_by = Some(line));
+                if let Some(history) = &mut self.history {
+                    history.link_reversal(record.client, record.tx, line);
+                }
+            }
+            "authorize" => {
+                let amount = record.amount.ok_or_else(|| anyhow!("Missing amount"))?;
+                if self.authorizations.contains_key(&record.tx) {
+                    return Err(anyhow!("Duplicate authorization"));
+                }
+                self.execute(record.client, record.tx, Operation::Authorize, amount)?;
+                let now = self.moment(record.client);
+                let expires = match self.auth_expiry {
+                    Some(Span::Records(records)) => Some(Deadline::Records(
+                        record.client,
+                        now.records.saturating_add(records),
+                    )),
+                    Some(Span::Secs(secs)) => now
+                        .timestamp
+                        .map(|timestamp| Deadline::Time(timestamp.saturating_add_secs(secs))),
+                    None => None,
+                };
+                self.authorizations.insert(
+                    record.tx,
+                    Authorization {
+                        client: record.client,
+                        amount,
+                        expires,
+                    },
+                );
+                if let Some(expires) = expires {
+                    self.expiries.insert((expires, record.tx));
+                }
+            }
+            "capture" | "release" => {
+                // Check the authorization is open and belongs to the right client
+                let authorization = self
+                    .authorizations
                     .get(&record.tx)
-                    .ok_or_else(|| anyhow!("Transaction not found"))?;
-                if client_id != record.client {
-                    return Err(anyhow!("Transaction does not belong to client"));
+                    .copied()
+                    .ok_or_else(|| anyhow!("Authorization not found"))?;
+                if authorization.client != record.client {
+                    return Err(anyhow!("Authorization does not belong to client"));
+                }
+                // Partial captures are allowed, and by default everything left is captured or released
+                let amount = record.amount.unwrap_or(authorization.amount);
+                if amount.is_zero() {
+                    return Err(anyhow!("Amount must be positive"));
+                }
+                if amount > authorization.amount {
+                    return Err(anyhow!("Amount exceeds authorized amount"));
                 }
 
-                let account = self.get_account(record.client);
-                account.execute(Operation::Chargeback, amount)?;
-                self.dispute_record.remove(&record.tx);
+                let operation = if record.command == "capture" {
+                    Operation::Capture
+                } else {
+                    Operation::Release
+                };
+                self.execute(record.client, record.tx, operation, amount)?;
+                self.settle_authorization(record.tx, amount);
+            }
+            "unlock" => {
+                // An admin action: the tx and the amount are ignored
+                if !self.allow_unlock {
+                    return Err(anyhow!("Unlock not allowed"));
+                }
+                // Only a manual review, outside of the input, can clear a chargeback
+                if self.lock_reasons.get(&record.client) == Some(&LockReason::Chargeback)
+                    || self.has_chargeback(record.client)
+                {
+                    return Err(anyhow!("Account locked by a chargeback"));
+                }
+                let account = self
+                    .accounts
+                    .get_mut(&record.client)
+                    .ok_or_else(|| anyhow!("Account not found"))?;
+                if !account.locked {
+                    return Err(anyhow!("Account not locked"));
+                }
+                let before = account.clone();
+                account.locked = false;
+                self.lock_reasons.remove(&record.client);
+                self.observers.on_unlock(&before, account);
+            }
+            "accrue" => {
+                // A control record: the client is ignored and the amount is the number of days
+                let days = record.amount.ok_or_else(|| anyhow!("Missing amount"))?;
+                self.accrue(record.tx, days)?;
             }
             _ => {
                 return Err(anyhow!("Unknown command"));
@@ -98,16 +579,466 @@ impl Engine {
         Ok(())
     }
 
-    /// Retrieve Account given its id. Create one if it does not exist
-    fn get_account(&mut self, account_id: u16) -> &mut Account {
-        self.accounts
-            .entry(account_id)
-            .or_insert(Account::new(account_id))
+    /// Credit `days` of interest to every account that is not locked, except the house account, on
+    /// behalf of transaction `tx`.
+    ///
+    /// All or nothing: if any account cannot be credited (e.g. because of an overflow), none is.
+    pub fn accrue(&mut self, tx: TxId, days: Decimal) -> Result<()> {
+        let interest = self
+            .interest
+            .ok_or_else(|| anyhow!("Interest not configured"))?;
+        if days <= Decimal::ZERO {
+            return Err(anyhow!("Days must be positive"));
+        }
+
+        // Compute and check every credit before applying any
+        let mut accounts: Vec<_> = self
+            .accounts
+            .values()
+            .filter(|account| !account.locked && !self.fees.is_house(account.id))
+            .collect();
+        accounts.sort_unstable_by_key(|account| account.id); // Deterministic history and journal
+        let mut credits = Vec::with_capacity(accounts.len());
+        for account in accounts {
+            let amount = interest.interest(account.available, days)?;
+            if amount.is_zero() {
+                continue;
+            }
+            let mut credited = account.clone();
+            credited.execute(Operation::Interest, amount, &self.policy)?;
+            self.limits.check_balance(account, &credited)?;
+            credits.push((account.id, amount));
+        }
+        for (client_id, amount) in credits {
+            self.execute(client_id, tx, Operation::Interest, amount)?;
+        }
+        Ok(())
+    }
+
+    /// Run `operation` on the Account of `client_id`, on behalf of transaction `tx`, following the
+    /// engine's policy.
+    ///
+    /// The Account is created if it does not exist. The operation must keep the Account within its
+    /// limits. Its fee, if any, is charged together with it and credited to the house account.
+    /// If successful, the operation and its fee are journaled and recorded in the clients' history.
+    fn execute(
+        &mut self,
+        client_id: ClientId,
+        tx: TxId,
+        operation: Operation,
+        amount: Decimal,
+    ) -> Result<()> {
+        let fee = self.fees.fee(client_id, operation, amount)?;
+        let now = self.moment(client_id);
+        let account = Self::open_account(
+            &mut self.accounts,
+            &self.limits,
+            &mut self.observers,
+            client_id,
+        );
+        self.limits.check(account, operation, amount, now)?;
+        let before = account.clone();
+        let fee = account.execute_with_fee(operation, amount, fee, &self.policy)?;
+        if let Err(err) = self.limits.check_balance(&before, account) {
+            *account = before;
+            return Err(err);
+        }
+        let after = account.clone();
+        if fee.is_zero() {
+            self.book(tx, operation, amount, &before, &after);
+        } else {
+            // Credit the house account, or roll the client's account back
+            let Some(house_id) = self.fees.house else {
+                self.accounts.insert(client_id, before);
+                return Err(anyhow!("House account not configured"));
+            };
+            let house = Self::open_account(
+                &mut self.accounts,
+                &self.limits,
+                &mut self.observers,
+                house_id,
+            );
+            let house_before = house.clone();
+            if let Err(err) = house
+                .execute(Operation::FeeIncome, fee, &self.policy)
+                .and_then(|()| self.limits.check_balance(&house_before, house))
+            {
+                *house = house_before;
+                self.accounts.insert(client_id, before);
+                return Err(err);
+            }
+            let house = house.clone();
+
+            // The operation and its fee are itemized, with the balances right after each of them
+            let mut charged = after.clone();
+            charged.available += fee;
+            charged.total += fee;
+            self.book(tx, operation, amount, &before, &charged);
+            self.book(tx, Operation::Fee, fee, &charged, &after);
+            self.book(tx, Operation::FeeIncome, fee, &house_before, &house);
+        }
+
+        // Only a chargeback locks an account here, and it is the reason of the lock from then on,
+        // whatever locked the account before
+        if operation == Operation::Chargeback {
+            self.lock_reasons.insert(client_id, LockReason::Chargeback);
+            if !before.locked {
+                self.observers
+                    .on_lock(&before, &after, LockReason::Chargeback);
+            }
+        }
+        Ok(())
+    }
+
+    /// Account of `client_id`, created with its overdraft if it does not exist
+    fn open_account<'a>(
+        accounts: &'a mut HashMap<ClientId, Account>,
+        limits: &Limits,
+        observers: &mut Observers,
+        client_id: ClientId,
+    ) -> &'a mut Account {
+        match accounts.entry(client_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let overdraft = limits.get(client_id).overdraft.unwrap_or_default();
+                let account = entry.insert(Account::new(client_id).with_overdraft(overdraft));
+                observers.on_account_created(account);
+                account
+            }
+        }
+    }
+
+    /// Keep track of a successful `operation`, moving the Account from `before` to `account`
+    fn book(
+        &mut self,
+        tx: TxId,
+        operation: Operation,
+        amount: Decimal,
+        before: &Account,
+        account: &Account,
+    ) {
+        self.observers
+            .on_operation(tx, operation, amount, before, account);
+        let now = self.moment(account.id);
+        self.limits.record(account.id, tx, operation, amount, now);
+        if let Some(history) = &mut self.history {
+            history.record(tx, operation, amount, account, self.timestamp);
+        }
+        if let Some(timestamp) = self.timestamp {
+            self.last_activity.insert(account.id, timestamp);
+        }
+        self.flows
+            .entry(account.id)
+            .or_default()
+            .add(operation, amount);
+        if let Some(journal) = &mut self.journal {
+            match operation {
+                Operation::ReverseDeposit | Operation::ReverseWithdrawal => {
+                    journal.post_reversal(tx, account.id, operation, amount, self.line)
+                }
+                _ => journal.post(tx, account.id, operation, amount),
+            }
+        }
     }
 
     /// Register transaction in our internal hashmap
-    fn register_transaction(&mut self, tx: u32, client_id: u16, amount: Decimal) {
-        self.tx_record.insert(tx, (client_id, amount)); // tx are supposed to be unique, so insert is never updating
+    fn register_transaction(
+        &mut self,
+        tx: TxId,
+        client_id: ClientId,
+        kind: TxKind,
+        amount: Decimal,
+    ) {
+        let tx_record = TxRecord {
+            client: client_id,
+            kind,
+            amount,
+            timestamp: self.timestamp,
+            reversed_by: None,
+        };
+        self.tx_record.insert(tx, tx_record); // tx are supposed to be unique, so insert is never updating
+    }
+
+    /// Retrieve the transaction referenced by `record`, checking it belongs to the right client
+    fn get_transaction(&self, record: &Record) -> Result<TxRecord> {
+        let tx_record = *self
+            .tx_record
+            .get(&record.tx)
+            .ok_or_else(|| anyhow!("Transaction not found"))?;
+        if tx_record.client != record.client {
+            return Err(anyhow!("Transaction does not belong to client"));
+        }
+        if let (Some(timestamp), Some(recorded)) = (record.timestamp, tx_record.timestamp) {
+            if timestamp < recorded {
+                return Err(anyhow!("Timestamp before referenced transaction"));
+            }
+        }
+        Ok(tx_record)
+    }
+
+    /// Apply `update` to a recorded transaction
+    fn update_transaction(&mut self, tx: TxId, update: impl FnOnce(&mut TxRecord)) {
+        if let Some(tx_record) = self.tx_record.get_mut(&tx) {
+            update(tx_record);
+        }
+    }
+
+    /// Amount of transaction `tx` currently under dispute, zero if it is not disputed
+    fn disputed_amount(&self, tx: TxId) -> Decimal {
+        self.dispute_record.get(&tx).copied().unwrap_or_default()
+    }
+
+    /// Whether any transaction of the client was charged back
+    fn has_chargeback(&self, client_id: ClientId) -> bool {
+        self.charged_back.keys().any(|tx| {
+            self.tx_record
+                .get(tx)
+                .is_some_and(|tx_record| tx_record.client == client_id)
+        })
+    }
+
+    /// Amount of transaction `tx` charged back so far, zero if it never was
+    fn charged_back_amount(&self, tx: TxId) -> Decimal {
+        self.charged_back.get(&tx).copied().unwrap_or_default()
+    }
+
+    /// Record a resolve or a chargeback of `amount` on `tx`, `open` being the amount under dispute
+    /// before it
+    fn settle_dispute(&mut self, client_id: ClientId, tx: TxId, open: Decimal, amount: Decimal) {
+        let stats = self.dispute_stats.entry(client_id).or_default();
+        stats.disputed -= amount;
+        if open == amount {
+            self.dispute_record.remove(&tx);
+            stats.open_disputes -= 1;
+        } else {
+            self.dispute_record.insert(tx, open - amount);
+        }
+        self.notify_dispute_change(client_id, tx, open, open - amount);
+    }
+
+    /// Tell observers the amount under dispute for `tx` went from `before` to `after`
+    fn notify_dispute_change(
+        &mut self,
+        client_id: ClientId,
+        tx: TxId,
+        before: Decimal,
+        after: Decimal,
+    ) {
+        let state = |open: Decimal| {
+            if open.is_zero() {
+                DisputeState::Closed
+            } else {
+                DisputeState::Open(open)
+            }
+        };
+        self.observers
+            .on_dispute_change(client_id, tx, state(before), state(after));
+    }
+
+    /// Lock the client's account if its dispute statistics go beyond the auto-lock thresholds
+    fn apply_auto_lock(&mut self, client_id: ClientId) {
+        let Some(reason) = self
+            .dispute_stats
+            .get(&client_id)
+            .and_then(|stats| self.auto_lock.check(stats))
+        else {
+            return;
+        };
+        if let Some(account) = self.accounts.get_mut(&client_id) {
+            if !account.locked {
+                let before = account.clone();
+                account.locked = true;
+                self.lock_reasons.insert(client_id, reason);
+                self.observers.on_lock(&before, account, reason);
+            }
+        }
+    }
+
+    /// Record a capture or a release of `amount` on authorization `tx`, closing it if nothing is left
+    fn settle_authorization(&mut self, tx: TxId, amount: Decimal) {
+        if let Some(authorization) = self.authorizations.get_mut(&tx) {
+            authorization.amount -= amount;
+            if authorization.amount.is_zero() {
+                self.authorizations.remove(&tx);
+            }
+        }
+    }
+
+    /// Release whatever is left of the authorizations past their expiry, as of a record of
+    /// `client_id`: those of the client past their number of records, and any past their time.
+    ///
+    /// If a release fails (e.g. the account is locked and the policy forbids it), the authorization
+    /// stays open and can still be captured or released explicitly.
+    fn expire_authorizations(&mut self, client_id: ClientId) {
+        let now = self.moment(client_id);
+        let mut expired: Vec<_> = self
+            .expiries
+            .range(
+                (Deadline::Records(client_id, 0), 0)
+                    ..(Deadline::Records(client_id, now.records), 0),
+            )
+            .copied()
+            .collect();
+        if let Some(timestamp) = self.timestamp {
+            expired.extend(self.expiries.range(..(Deadline::Time(timestamp), 0)));
+        }
+        for (expires, tx) in expired {
+            self.expiries.remove(&(expires, tx));
+            let Some(authorization) = self.authorizations.get(&tx).copied() else {
+                continue; // Already captured or released
+            };
+            let (client, amount) = (authorization.client, authorization.amount);
+            if self.execute(client, tx, Operation::Release, amount).is_ok() {
+                self.settle_authorization(tx, amount);
+            }
+        }
+    }
+
+    /// Audit a single client's account.
+    ///
+    /// Besides the account's own invariants (see `Account::check_invariants`), held funds must
+    /// match the sum of the client's open disputes and authorizations, and a locked account must have
+    /// a lock reason, i.e. a chargeback or an auto-lock threshold crossed.
+    /// If the journal is enabled, balances must match the ones derived from it.
+    pub fn audit_client(&self, client_id: ClientId) -> Result<()> {
+        let Some(account) = self.accounts.get(&client_id) else {
+            return Ok(()); // Nothing to check
+        };
+        let disputed = self
+            .dispute_record
+            .iter()
+            .filter(|(tx, _)| {
+                self.tx_record.get(tx).map(|tx_record| tx_record.client) == Some(client_id)
+            })
+            .map(|(_, amount)| *amount)
+            .sum::<Decimal>();
+        let authorized = self
+            .authorizations
+            .values()
+            .filter(|authorization| authorization.client == client_id)
+            .map(|authorization| authorization.amount)
+            .sum::<Decimal>();
+        let charged_back = self
+            .charged_back
+            .keys()
+            .any(|tx| self.tx_record.get(tx).map(|tx_record| tx_record.client) == Some(client_id));
+        self.check_account(account, disputed + authorized, charged_back)
+            .with_context(|| format!("Client {}", client_id))
+    }
+
+    /// Audit every account, see `audit_client`.
+    ///
+    /// All the violations are returned, not just the first one.
+    pub fn audit(&self) -> Vec<anyhow::Error> {
+        // Collect what is needed from transactions in one pass, rather than once per client
+        let disputed = self.get_open_disputes();
+        let authorized = self.get_open_authorizations();
+        let charged_back: HashSet<_> = self
+            .charged_back
+            .keys()
+            .filter_map(|tx| self.tx_record.get(tx).map(|tx_record| tx_record.client))
+            .collect();
+
+        let mut violations: Vec<_> = self
+            .accounts
+            .values()
+            .filter_map(|account| {
+                let held = disputed.get(&account.id).copied().unwrap_or_default()
+                    + authorized.get(&account.id).copied().unwrap_or_default();
+                self.check_account(account, held, charged_back.contains(&account.id))
+                    .with_context(|| format!("Client {}", account.id))
+                    .err()
+            })
+            .collect();
+        if let Some(Err(err)) = self.journal.as_ref().map(Journal::verify) {
+            violations.push(err);
+        }
+        violations
+    }
+
+    /// Check an account against its invariants, the sum of its open disputes and authorizations,
+    /// whether it had a chargeback and the journal
+    fn check_account(&self, account: &Account, held: Decimal, charged_back: bool) -> Result<()> {
+        account.check_invariants(&self.policy)?;
+        if account.held != held {
+            return Err(anyhow!(
+                "Held funds do not match open disputes and authorizations"
+            ));
+        }
+        if account.locked {
+            match self.lock_reasons.get(&account.id) {
+                None => return Err(anyhow!("Account locked without a reason")),
+                Some(LockReason::Chargeback) if !charged_back => {
+                    return Err(anyhow!("Account locked without a chargeback"))
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some(journal) = &self.journal {
+            journal.verify_account(account)?;
+        }
+        Ok(())
+    }
+
+    /// Sum of the amounts currently under dispute, per client
+    pub fn get_open_disputes(&self) -> HashMap<ClientId, Decimal> {
+        let mut disputed: HashMap<ClientId, Decimal> = HashMap::new();
+        for (tx, amount) in &self.dispute_record {
+            if let Some(tx_record) = self.tx_record.get(tx) {
+                *disputed.entry(tx_record.client).or_default() += amount;
+            }
+        }
+        disputed
+    }
+
+    /// Number of transactions currently under dispute
+    pub fn count_open_disputes(&self) -> usize {
+        self.dispute_record.len()
+    }
+
+    /// Sum of the amounts currently held by authorizations, per client
+    pub fn get_open_authorizations(&self) -> HashMap<ClientId, Decimal> {
+        let mut authorized: HashMap<ClientId, Decimal> = HashMap::new();
+        for authorization in self.authorizations.values() {
+            *authorized.entry(authorization.client).or_default() += authorization.amount;
+        }
+        authorized
+    }
+
+    /// Utility function returning the money that entered or left each client's account
+    pub fn get_flows(&self) -> &HashMap<ClientId, Flows> {
+        &self.flows
+    }
+
+    /// Utility function returning the timestamp of the latest operation on each account, for the
+    /// accounts changed by timestamped records
+    pub fn get_last_activity(&self) -> &HashMap<ClientId, Timestamp> {
+        &self.last_activity
+    }
+
+    /// Whether any timestamped record was applied
+    pub fn has_timestamps(&self) -> bool {
+        self.latest.is_some()
+    }
+
+    /// Utility function returning why each locked account got locked
+    pub fn get_lock_reasons(&self) -> &HashMap<ClientId, LockReason> {
+        &self.lock_reasons
+    }
+
+    /// Utility function returning the alerts raised by risk rules
+    pub fn get_alerts(&self) -> &[Alert] {
+        self.risk.alerts()
+    }
+
+    /// Utility function returning the double-entry journal, if enabled
+    pub fn get_journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Utility function returning the per-client history, if enabled
+    pub fn get_history(&self) -> Option<&History> {
+        self.history.as_ref()
     }
 
     /// This is just a placeholder for code running `Engine` as a standalone service.
@@ -121,7 +1052,7 @@ impl Engine {
     /// Utility function returning all the known accounts.
     ///
     /// The idea is to use the returned value to print accounts out in a format of user's choosing.
-    pub fn get_accounts(&self) -> &HashMap<u16, Account> {
+    pub fn get_accounts(&self) -> &HashMap<ClientId, Account> {
         &self.accounts
     }
 }
@@ -129,6 +1060,12 @@ impl Engine {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fees::Fee;
+    use crate::interest::DayCount;
+    use crate::limits::AccountLimits;
+    use crate::observer::EngineObserver;
+    use crate::risk::{Action, RiskRule, Velocity, Verdict};
+    use std::sync::Mutex;
 
     #[test]
     fn test_deposit_ok() {
@@ -138,6 +1075,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&record).unwrap();
         assert_eq!(engine.tx_record.len(), 1);
@@ -153,6 +1092,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         assert!(engine.process(&record).is_err());
         assert_eq!(engine.tx_record.len(), 0);
@@ -168,16 +1109,20 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         let record = Record {
             client: 2,
             command: "withdrawal".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 3,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
         engine.process(&record).unwrap();
-        assert_eq!(engine.tx_record.len(), 1); // it's the deposit
+        assert_eq!(engine.tx_record.len(), 2);
         assert_eq!(engine.accounts.len(), 1);
         assert_eq!(engine.dispute_record.len(), 0);
     }
@@ -190,6 +1135,8 @@ mod tests {
             command: "withdrawal".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         assert!(engine.process(&record).is_err());
         assert_eq!(engine.tx_record.len(), 0);
@@ -205,6 +1152,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -213,6 +1162,8 @@ mod tests {
             command: "dispute".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&record).unwrap();
         assert_eq!(engine.tx_record.len(), 1);
@@ -228,6 +1179,8 @@ mod tests {
             command: "dispute".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         assert!(engine.process(&record).is_err());
         assert_eq!(engine.tx_record.len(), 0);
@@ -243,6 +1196,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -251,6 +1206,8 @@ mod tests {
             command: "dispute".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         assert!(engine.process(&record).is_err());
         assert_eq!(engine.tx_record.len(), 1);
@@ -266,6 +1223,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -275,6 +1234,8 @@ mod tests {
             command: "dispute".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&dispute_record).unwrap();
         assert_eq!(engine.dispute_record.len(), 1);
@@ -285,6 +1246,8 @@ mod tests {
             command: "resolve".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&record).unwrap();
         assert_eq!(engine.tx_record.len(), 1);
@@ -300,6 +1263,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -309,6 +1274,8 @@ mod tests {
             command: "resolve".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         assert!(engine.process(&record).is_err());
         assert_eq!(engine.tx_record.len(), 1);
@@ -326,6 +1293,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -335,6 +1304,8 @@ mod tests {
             command: "dispute".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&dispute_record).unwrap();
         assert_eq!(engine.dispute_record.len(), 1);
@@ -345,6 +1316,8 @@ mod tests {
             command: "chargeback".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&record).unwrap();
         assert_eq!(engine.tx_record.len(), 1);
@@ -360,6 +1333,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -369,6 +1344,8 @@ mod tests {
             command: "chargeback".to_string(),
             amount: None,
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         assert!(engine.process(&record).is_err());
         assert_eq!(engine.tx_record.len(), 1);
@@ -378,6 +1355,800 @@ mod tests {
 
     // ToDo: test_chargeback_wrong_client
 
+    // A dispute opened before the account was locked can still be settled
+    #[test]
+    fn test_chargeback_after_lock() {
+        let mut engine = Engine::new();
+        for tx in 1..=2 {
+            let deposit_record = Record {
+                client: 1,
+                command: "deposit".to_string(),
+                amount: Some(Decimal::new(100, 1)),
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&deposit_record).unwrap();
+            let dispute_record = Record {
+                client: 1,
+                command: "dispute".to_string(),
+                amount: None,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&dispute_record).unwrap();
+        }
+        for tx in 1..=2 {
+            let record = Record {
+                client: 1,
+                command: "chargeback".to_string(),
+                amount: None,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        let account = &engine.accounts[&1];
+        assert!(account.locked);
+        assert_eq!(account.total, Decimal::ZERO);
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(engine.dispute_record.len(), 0);
+    }
+
+    // With negative balances allowed, a chargeback on spent funds is recorded as a debt
+    #[test]
+    fn test_chargeback_negative_balance() {
+        let policy = AccountPolicy {
+            allow_negative: true,
+            ..Default::default()
+        };
+        let mut engine = Engine::with_policy(policy);
+        let records = [
+            ("deposit", Some(Decimal::new(100, 1)), 1),
+            ("withdrawal", Some(Decimal::new(60, 1)), 2),
+            ("dispute", None, 1),
+            ("chargeback", None, 1),
+        ];
+        for (command, amount, tx) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        let account = &engine.accounts[&1];
+        assert!(account.locked);
+        assert_eq!(account.total, Decimal::new(-60, 1));
+        assert_eq!(account.debt(), Decimal::new(60, 1));
+    }
+
+    #[test]
+    fn test_partial_dispute() {
+        let mut engine = Engine::new();
+        let records = [
+            ("deposit", Some(Decimal::new(100, 1)), 1),
+            ("dispute", Some(Decimal::new(30, 1)), 1),
+            ("dispute", Some(Decimal::new(20, 1)), 1),
+            ("resolve", Some(Decimal::new(10, 1)), 1),
+            ("chargeback", None, 1),
+        ];
+        for (command, amount, tx) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        let account = &engine.accounts[&1];
+        assert!(account.locked);
+        assert_eq!(account.total, Decimal::new(60, 1));
+        assert_eq!(account.available, Decimal::new(60, 1));
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(engine.dispute_record.len(), 0);
+        assert_eq!(engine.charged_back[&1], Decimal::new(40, 1));
+    }
+
+    #[test]
+    fn test_partial_dispute_too_big() {
+        let mut engine = Engine::new();
+        let deposit_record = Record {
+            client: 1,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::new(100, 1)),
+            tx: 1,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&deposit_record).unwrap();
+        let mut record = Record {
+            client: 1,
+            command: "dispute".to_string(),
+            amount: Some(Decimal::new(80, 1)),
+            tx: 1,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+
+        // Only 2.0 left to dispute
+        record.amount = Some(Decimal::new(30, 1));
+        assert!(engine.process(&record).is_err());
+        record.amount = Some(Decimal::ZERO);
+        assert!(engine.process(&record).is_err());
+        assert_eq!(engine.dispute_record[&1], Decimal::new(80, 1));
+
+        // Cannot resolve more than what is under dispute
+        record.command = "resolve".to_string();
+        record.amount = Some(Decimal::new(90, 1));
+        assert!(engine.process(&record).is_err());
+
+        // Dispute the rest: nothing left afterwards
+        record.command = "dispute".to_string();
+        record.amount = None;
+        engine.process(&record).unwrap();
+        assert_eq!(engine.dispute_record[&1], Decimal::new(100, 1));
+        assert!(engine.process(&record).is_err());
+        assert_eq!(engine.accounts[&1].held, Decimal::new(100, 1));
+    }
+
+    #[test]
+    fn test_reversal() {
+        let mut engine = Engine::new().with_journal(true).with_history(true);
+        let records = [
+            ("deposit", Some(Decimal::new(100, 1)), 1),
+            ("withdrawal", Some(Decimal::new(30, 1)), 2),
+            ("reversal", None, 2),
+            ("deposit", Some(Decimal::new(20, 1)), 3),
+            ("reversal", None, 3),
+        ];
+        for (command, amount, tx) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        let account = &engine.accounts[&1];
+        assert!(!account.locked);
+        assert_eq!(account.total, Decimal::new(100, 1));
+        assert_eq!(account.available, Decimal::new(100, 1));
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(engine.tx_record[&2].reversed_by, Some(3));
+        assert_eq!(engine.tx_record[&3].reversed_by, Some(5));
+        assert_eq!(engine.tx_record[&1].reversed_by, None);
+        let history = engine.get_history().unwrap();
+        let reversals: Vec<_> = history
+            .statement(1, 0..=TxId::MAX)
+            .map(|entry| (entry.tx, entry.reversal))
+            .collect();
+        assert_eq!(
+            reversals,
+            [
+                (1, None),
+                (2, Some(3)),
+                (2, Some(3)),
+                (3, Some(5)),
+                (3, Some(5))
+            ]
+        );
+        let journal = engine.get_journal().unwrap().entries();
+        assert_eq!(journal[2].reversal, Some(3));
+        assert_eq!(journal[1].reversal, None);
+        let flows = engine.get_flows()[&1];
+        assert_eq!(flows.deposits, Decimal::new(100, 1));
+        assert_eq!(flows.withdrawals, Decimal::ZERO);
+        assert!(engine.audit().is_empty());
+    }
+
+    #[test]
+    fn test_reversal_line() {
+        let mut engine = Engine::new().with_journal(true);
+        engine
+            .process(&Record::new("deposit", 1, 1, Some(Decimal::TEN)))
+            .unwrap();
+        // A row that cannot be read, then a batch rolled back with one
+        engine.skip(1);
+        engine.skip(2);
+        engine
+            .process(&Record::new("reversal", 1, 1, None))
+            .unwrap();
+        assert_eq!(engine.tx_record[&1].reversed_by, Some(5));
+        assert_eq!(engine.get_journal().unwrap().entries()[1].reversal, Some(5));
+    }
+
+    #[test]
+    fn test_reversal_rejected() {
+        let mut engine = Engine::new();
+        let mut record = Record {
+            client: 1,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::new(100, 1)),
+            tx: 1,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+        record.command = "dispute".to_string();
+        record.amount = Some(Decimal::ONE);
+        engine.process(&record).unwrap();
+
+        // Under dispute
+        record.command = "reversal".to_string();
+        record.amount = None;
+        assert!(engine.process(&record).is_err());
+        record.command = "resolve".to_string();
+        engine.process(&record).unwrap();
+
+        // Already reversed, and a reversed transaction cannot be disputed
+        record.command = "reversal".to_string();
+        engine.process(&record).unwrap();
+        assert!(engine.process(&record).is_err());
+        record.command = "dispute".to_string();
+        assert!(engine.process(&record).is_err());
+        assert_eq!(engine.accounts[&1].total, Decimal::ZERO);
+
+        // Wrong client and unknown transaction
+        record.command = "reversal".to_string();
+        record.client = 2;
+        assert!(engine.process(&record).is_err());
+        record.tx = 2;
+        assert!(engine.process(&record).is_err());
+    }
+
+    #[test]
+    fn test_dispute_withdrawal() {
+        let mut engine = Engine::new();
+        let mut record = Record {
+            client: 1,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::new(100, 1)),
+            tx: 1,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+        record.command = "withdrawal".to_string();
+        record.tx = 2;
+        engine.process(&record).unwrap();
+        record.command = "dispute".to_string();
+        record.amount = None;
+        assert!(engine.process(&record).is_err());
+        assert_eq!(engine.dispute_record.len(), 0);
+    }
+
+    #[test]
+    fn test_authorization() {
+        let mut engine = Engine::new().with_journal(true).with_paranoid(true);
+        let records = [
+            ("deposit", 1, Some(Decimal::new(100, 1)), 1),
+            ("authorize", 1, Some(Decimal::new(60, 1)), 2),
+            ("authorize", 1, Some(Decimal::new(30, 1)), 3),
+            ("capture", 1, Some(Decimal::new(20, 1)), 2),
+            ("capture", 1, Some(Decimal::new(10, 1)), 2),
+            ("release", 1, None, 3),
+            ("dispute", 1, Some(Decimal::new(10, 1)), 1),
+        ];
+        for (command, client, amount, tx) in records {
+            let record = Record {
+                client,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        let account = &engine.accounts[&1];
+        assert!(!account.locked);
+        assert_eq!(account.total, Decimal::new(70, 1));
+        assert_eq!(account.available, Decimal::new(30, 1));
+        assert_eq!(account.held, Decimal::new(40, 1));
+        assert_eq!(engine.authorizations.len(), 1);
+        assert_eq!(engine.authorizations[&2].amount, Decimal::new(30, 1));
+        assert_eq!(engine.dispute_record[&1], Decimal::new(10, 1));
+        assert_eq!(engine.get_open_authorizations()[&1], Decimal::new(30, 1));
+        assert!(engine.audit().is_empty());
+
+        // Holds are not disputes, and the other way around
+        let mut record = Record {
+            client: 1,
+            command: "resolve".to_string(),
+            amount: None,
+            tx: 2,
+            batch: None,
+            timestamp: None,
+        };
+        assert!(engine.process(&record).is_err());
+        record.command = "release".to_string();
+        record.tx = 1;
+        assert!(engine.process(&record).is_err());
+    }
+
+    #[test]
+    fn test_authorization_rejected() {
+        let mut engine = Engine::new();
+        let mut record = Record {
+            client: 1,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::new(100, 1)),
+            tx: 1,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+
+        // More than available
+        record.command = "authorize".to_string();
+        record.tx = 2;
+        record.amount = Some(Decimal::new(110, 1));
+        assert!(engine.process(&record).is_err());
+        record.amount = Some(Decimal::new(50, 1));
+        engine.process(&record).unwrap();
+        assert!(engine.process(&record).is_err()); // Duplicate
+
+        // More than authorized, wrong client, unknown authorization
+        record.command = "capture".to_string();
+        record.amount = Some(Decimal::new(60, 1));
+        assert!(engine.process(&record).is_err());
+        record.amount = None;
+        record.client = 2;
+        assert!(engine.process(&record).is_err());
+        record.client = 1;
+        record.tx = 3;
+        assert!(engine.process(&record).is_err());
+
+        // Fully captured: nothing left to release
+        record.tx = 2;
+        engine.process(&record).unwrap();
+        record.command = "release".to_string();
+        assert!(engine.process(&record).is_err());
+        assert_eq!(engine.accounts[&1].total, Decimal::new(50, 1));
+        assert!(engine.authorizations.is_empty());
+    }
+
+    #[test]
+    fn test_authorization_expiry() {
+        let mut engine = Engine::new().with_auth_expiry(Some(Span::Records(2)));
+        let records = [
+            ("deposit", Some(Decimal::new(100, 1)), 1),
+            ("authorize", Some(Decimal::new(60, 1)), 2),
+            ("authorize", Some(Decimal::new(30, 1)), 3),
+            ("capture", Some(Decimal::new(20, 1)), 2),
+        ];
+        for (command, amount, tx) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        assert_eq!(engine.accounts[&1].held, Decimal::new(70, 1));
+
+        // Records of other clients don't count
+        let mut record = Record {
+            client: 2,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::ONE),
+            tx: 5,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+        record.tx = 6;
+        engine.process(&record).unwrap();
+        assert_eq!(engine.accounts[&1].held, Decimal::new(70, 1));
+
+        // Two records of the client after it, what is left of tx 2 is released
+        record = Record {
+            client: 1,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::ONE),
+            tx: 4,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+        assert_eq!(engine.accounts[&1].held, Decimal::new(30, 1));
+        assert_eq!(engine.accounts[&1].available, Decimal::new(60, 1));
+        record.command = "capture".to_string();
+        record.tx = 2;
+        record.amount = None;
+        assert!(engine.process(&record).is_err());
+        assert!(engine.authorizations.is_empty());
+        assert_eq!(engine.accounts[&1].held, Decimal::ZERO);
+        assert_eq!(engine.accounts[&1].total, Decimal::new(90, 1));
+        assert!(engine.audit().is_empty());
+    }
+
+    #[test]
+    fn test_authorization_expiry_time() {
+        let mut engine = Engine::new().with_auth_expiry(Some(Span::Secs(60)));
+        let records = [
+            ("deposit", 1, 1, Some(Decimal::TEN), "1000"),
+            ("authorize", 1, 2, Some(Decimal::new(6, 0)), "1000"),
+            ("deposit", 1, 3, Some(Decimal::ONE), "1030"),
+            ("deposit", 1, 4, Some(Decimal::ONE), "1060"),
+        ];
+        for (command, client, tx, amount, timestamp) in records {
+            let record = Record::new(command, client, tx, amount).at(timestamp.parse().unwrap());
+            engine.process(&record).unwrap();
+        }
+        // However many records went by
+        assert_eq!(engine.accounts[&1].held, Decimal::new(6, 0));
+
+        // Time passes for every client
+        let record = Record::new("deposit", 2, 5, Some(Decimal::ONE)).at("1061".parse().unwrap());
+        engine.process(&record).unwrap();
+        assert_eq!(engine.accounts[&1].held, Decimal::ZERO);
+        assert_eq!(engine.accounts[&1].available, Decimal::new(12, 0));
+        assert!(engine.authorizations.is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let default = AccountLimits {
+            max_withdrawal: Some(Decimal::new(50, 1)),
+            ..Default::default()
+        };
+        let client = AccountLimits {
+            max_balance: Some(Decimal::new(150, 1)),
+            ..Default::default()
+        };
+        let interest = InterestPolicy {
+            rate: Decimal::new(36, 0),
+            day_count: DayCount::Actual360,
+        };
+        let mut engine = Engine::new()
+            .with_limits(Limits::new(default).with_client(2, client))
+            .with_interest(Some(interest));
+        let records = [
+            ("deposit", 1, Some(Decimal::new(200, 1)), 1, true),
+            ("withdrawal", 1, Some(Decimal::new(60, 1)), 2, false),
+            ("withdrawal", 1, Some(Decimal::new(50, 1)), 3, true),
+            ("deposit", 2, Some(Decimal::new(200, 1)), 4, false),
+            ("deposit", 2, Some(Decimal::new(150, 1)), 5, true),
+            ("withdrawal", 2, Some(Decimal::new(60, 1)), 6, true),
+            ("reversal", 2, None, 6, true),
+            ("deposit", 2, Some(Decimal::ONE), 7, false),
+            // Interest would take client 2 over its balance limit, so no client gets any
+            ("accrue", 0, Some(Decimal::TEN), 8, false),
+        ];
+        for (command, client, amount, tx, ok) in records {
+            let record = Record {
+                client,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            assert_eq!(engine.process(&record).is_ok(), ok, "tx {}", tx);
+        }
+        assert_eq!(engine.accounts[&1].total, Decimal::new(150, 1));
+        assert_eq!(engine.accounts[&2].total, Decimal::new(150, 1));
+    }
+
+    #[test]
+    fn test_overdraft() {
+        let limits = Limits::default().with_client(
+            1,
+            AccountLimits {
+                overdraft: Some(Decimal::new(50, 1)),
+                ..Default::default()
+            },
+        );
+        let mut engine = Engine::new().with_limits(limits).with_paranoid(true);
+        let records = [
+            ("deposit", 1, Some(Decimal::new(20, 1)), 1, true),
+            ("withdrawal", 1, Some(Decimal::new(60, 1)), 2, true),
+            ("withdrawal", 1, Some(Decimal::new(20, 1)), 3, false),
+            ("deposit", 2, Some(Decimal::new(20, 1)), 4, true),
+            ("withdrawal", 2, Some(Decimal::new(30, 1)), 5, false),
+        ];
+        for (command, client, amount, tx, ok) in records {
+            let record = Record {
+                client,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            assert_eq!(engine.process(&record).is_ok(), ok, "tx {}", tx);
+        }
+        let account = &engine.accounts[&1];
+        assert_eq!(account.overdraft, Decimal::new(50, 1));
+        assert_eq!(account.overdrawn(), Decimal::new(40, 1));
+        assert_eq!(engine.accounts[&2].overdraft, Decimal::ZERO);
+        assert!(engine.audit().is_empty());
+    }
+
+    #[test]
+    fn test_fees() {
+        let mut fees = FeeSchedule::new(9);
+        fees.withdrawal = Some(Fee {
+            flat: Decimal::ONE,
+            ..Default::default()
+        });
+        fees.chargeback = Some(Fee {
+            flat: Decimal::new(50, 1),
+            ..Default::default()
+        });
+        let mut engine = Engine::new()
+            .with_fees(fees)
+            .with_journal(true)
+            .with_history(true)
+            .with_paranoid(true);
+        let records = [
+            ("deposit", Some(Decimal::new(100, 1)), 1, true),
+            ("withdrawal", Some(Decimal::new(100, 1)), 2, false), // Not enough left for the fee
+            ("withdrawal", Some(Decimal::new(40, 1)), 3, true),
+            ("dispute", None, 1, false), // Spent
+            ("deposit", Some(Decimal::new(50, 1)), 4, true),
+            ("dispute", None, 4, true),
+            ("chargeback", None, 4, true), // Fee capped to what is left
+            ("deposit", Some(Decimal::ONE), 5, false), // House account
+        ];
+        for (command, amount, tx, ok) in records {
+            let record = Record {
+                client: if tx == 5 { 9 } else { 1 },
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            assert_eq!(engine.process(&record).is_ok(), ok, "tx {}", tx);
+        }
+        let account = &engine.accounts[&1];
+        assert!(account.locked);
+        assert_eq!(account.total, Decimal::ZERO);
+        assert_eq!(engine.accounts[&9].total, Decimal::new(60, 1));
+        assert!(engine.audit().is_empty());
+
+        let history = engine.get_history().unwrap();
+        let statement: Vec<_> = history
+            .statement(1, 3..=3)
+            .map(|entry| (entry.operation, entry.amount, entry.available))
+            .collect();
+        assert_eq!(
+            statement,
+            vec![
+                (
+                    Operation::Withdraw,
+                    Decimal::new(40, 1),
+                    Decimal::new(60, 1)
+                ),
+                (Operation::Fee, Decimal::ONE, Decimal::new(50, 1)),
+            ]
+        );
+        assert_eq!(history.statement(9, 0..=TxId::MAX).count(), 2);
+        assert_eq!(engine.get_flows()[&1].fees, Decimal::new(60, 1));
+        assert_eq!(engine.get_flows()[&9].fees, Decimal::new(-60, 1));
+
+        // The house account earns no interest
+        let mut engine = engine.with_interest(Some(InterestPolicy {
+            rate: Decimal::ONE_HUNDRED,
+            day_count: DayCount::Actual365,
+        }));
+        engine.accrue(6, Decimal::new(365, 0)).unwrap();
+        assert_eq!(engine.accounts[&9].total, Decimal::new(60, 1));
+    }
+
+    #[test]
+    fn test_accrue() {
+        let interest = InterestPolicy {
+            rate: Decimal::new(36, 1),
+            day_count: DayCount::Actual360,
+        };
+        let mut engine = Engine::new()
+            .with_interest(Some(interest))
+            .with_journal(true)
+            .with_paranoid(true);
+        let records = [
+            ("deposit", 1, Some(Decimal::new(1000, 0)), 1),
+            ("deposit", 2, Some(Decimal::new(500, 0)), 2),
+            ("dispute", 2, None, 2),
+            ("chargeback", 2, None, 2),
+            ("deposit", 3, Some(Decimal::new(200, 0)), 3),
+            ("dispute", 3, Some(Decimal::new(100, 0)), 3),
+            ("accrue", 0, Some(Decimal::TEN), 4),
+        ];
+        for (command, client, amount, tx) in records {
+            let record = Record {
+                client,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        // 0.01% a day on available funds, none for locked accounts
+        assert_eq!(engine.accounts[&1].available, Decimal::new(1001, 0));
+        assert_eq!(engine.accounts[&2].total, Decimal::ZERO);
+        assert_eq!(engine.accounts[&3].available, Decimal::new(1001, 1));
+        assert_eq!(engine.accounts[&3].held, Decimal::new(100, 0));
+        assert_eq!(engine.get_flows()[&1].interest, Decimal::ONE);
+        assert_eq!(engine.get_flows()[&3].interest, Decimal::new(1, 1));
+        assert!(engine.audit().is_empty());
+
+        assert!(engine.accrue(5, Decimal::ZERO).is_err());
+        assert!(Engine::new().accrue(5, Decimal::ONE).is_err());
+
+        // The last account overflowing: none is credited
+        let account = engine.accounts.get_mut(&3).unwrap();
+        account.available = Decimal::MAX / Decimal::TWO;
+        account.total = Decimal::MAX;
+        assert!(engine.accrue(6, Decimal::TEN).is_err());
+        assert_eq!(engine.accounts[&1].available, Decimal::new(1001, 0));
+        assert_eq!(engine.get_flows()[&1].interest, Decimal::ONE);
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut engine = Engine::new();
+        let records = [
+            ("deposit", 1, Some(Decimal::new(100, 1)), 1),
+            ("deposit", 2, Some(Decimal::new(100, 1)), 2),
+            ("dispute", 1, Some(Decimal::new(50, 1)), 1),
+            ("dispute", 2, None, 2),
+            ("chargeback", 2, None, 2),
+        ];
+        for (command, client, amount, tx) in records {
+            let record = Record {
+                client,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+        assert!(engine.audit().is_empty());
+        assert!(engine.audit_client(1).is_ok());
+        assert!(engine.audit_client(2).is_ok());
+
+        // Held funds not matching disputes
+        engine.accounts.get_mut(&1).unwrap().held = Decimal::new(40, 1);
+        engine.accounts.get_mut(&1).unwrap().total = Decimal::new(90, 1);
+        assert!(engine.audit_client(1).is_err());
+        assert_eq!(engine.audit().len(), 1);
+
+        // Locked without a chargeback
+        engine.charged_back.clear();
+        assert!(engine.audit_client(2).is_err());
+        assert_eq!(engine.audit().len(), 2);
+    }
+
+    #[test]
+    fn test_paranoid() {
+        let mut engine = Engine::new().with_paranoid(true);
+        let record = Record {
+            client: 1,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::new(100, 1)),
+            tx: 1,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+
+        // Corrupt the account: the next operation reports it, even if rejected, but apart from
+        // its own outcome
+        engine.accounts.get_mut(&1).unwrap().total = Decimal::ZERO;
+        let record = Record {
+            client: 1,
+            command: "withdrawal".to_string(),
+            amount: Some(Decimal::new(200, 1)),
+            tx: 2,
+            batch: None,
+            timestamp: None,
+        };
+        let err = engine.process(&record).unwrap_err();
+        assert_eq!(err.to_string(), "Insufficient funds");
+        let record = Record {
+            client: 1,
+            command: "deposit".to_string(),
+            amount: Some(Decimal::ONE),
+            tx: 3,
+            batch: None,
+            timestamp: None,
+        };
+        engine.process(&record).unwrap();
+        let violations = engine.take_violations();
+        assert_eq!(
+            violations
+                .iter()
+                .map(|violation| violation.tx)
+                .collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert!(violations[0].error.starts_with("Client 1"));
+        assert!(engine.take_violations().is_empty());
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut engine = Engine::new().with_journal(true).with_paranoid(true);
+        let records = [
+            ("deposit", Some(Decimal::new(100, 1)), 1),
+            ("withdrawal", Some(Decimal::new(20, 1)), 2),
+            ("withdrawal", Some(Decimal::new(200, 1)), 3), // rejected, not journaled
+            ("dispute", Some(Decimal::new(50, 1)), 1),
+            ("resolve", Some(Decimal::new(10, 1)), 1),
+            ("chargeback", None, 1),
+        ];
+        for (command, amount, tx) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            let _ = engine.process(&record);
+        }
+        let journal = engine.get_journal().unwrap();
+        assert_eq!(journal.entries().len(), 5);
+        assert!(engine.audit().is_empty());
+
+        // Balances not explained by the journal
+        engine.accounts.get_mut(&1).unwrap().available = Decimal::new(50, 1);
+        engine.accounts.get_mut(&1).unwrap().total = Decimal::new(50, 1);
+        assert!(engine.audit_client(1).is_err());
+    }
+
+    #[test]
+    fn test_process_batch() {
+        let mut engine = Engine::new().with_journal(true).with_history(true);
+        let deposit =
+            |client, tx| Record::new("deposit", client, tx, Some(Decimal::TEN)).in_batch(1);
+        engine
+            .process_batch(&[deposit(1, 1), deposit(2, 2)])
+            .unwrap();
+        assert_eq!(engine.accounts.len(), 2);
+
+        // The withdrawal is rejected: everything is rolled back
+        let batch = [
+            deposit(1, 3),
+            deposit(3, 4),
+            Record::new("dispute", 1, 1, None).in_batch(2),
+            Record::new("withdrawal", 2, 5, Some(Decimal::ONE_HUNDRED)).in_batch(2),
+        ];
+        let err = engine.process_batch(&batch).unwrap_err();
+        assert_eq!(err.index, 3);
+        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.accounts[&1].total, Decimal::TEN);
+        assert_eq!(engine.accounts[&1].held, Decimal::ZERO);
+        assert_eq!(engine.tx_record.len(), 2);
+        assert_eq!(engine.dispute_record.len(), 0);
+        assert_eq!(engine.get_journal().unwrap().entries().len(), 2);
+        assert!(engine.audit().is_empty());
+    }
+
     #[test]
     fn test_get_accounts() {
         let mut engine = Engine::new();
@@ -386,6 +2157,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -394,6 +2167,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 2,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -408,6 +2183,8 @@ mod tests {
             command: "deposit".to_string(),
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         engine.process(&deposit_record).unwrap();
 
@@ -416,10 +2193,367 @@ mod tests {
             command: "withdraw".to_string(), // it's withdrawal
             amount: Some(Decimal::new(100, 1)),
             tx: 1,
+            batch: None,
+            timestamp: None,
         };
         assert!(engine.process(&record).is_err());
         assert_eq!(engine.accounts.len(), 1);
         assert_eq!(engine.tx_record.len(), 1);
         assert_eq!(engine.dispute_record.len(), 0);
     }
+
+    #[test]
+    fn test_timestamps() {
+        let now = "2024-01-01T12:00:00Z".parse().unwrap();
+        let mut engine = Engine::new().with_clock_skew(60).with_now(Some(now));
+        let records = [
+            ("deposit", 1, "2024-01-01T10:00:00Z", Ok(())),
+            ("deposit", 2, "2024-01-01T11:00:00Z", Ok(())),
+            // Within the clock skew of the latest one
+            ("deposit", 3, "2024-01-01T10:59:30Z", Ok(())),
+            (
+                "deposit",
+                4,
+                "2024-01-01T10:58:59Z",
+                Err("Timestamp out of order"),
+            ),
+            (
+                "deposit",
+                5,
+                "2024-01-01T12:01:01Z",
+                Err("Timestamp in the future"),
+            ),
+            ("dispute", 2, "2024-01-01T11:00:00Z", Ok(())),
+            ("resolve", 2, "1704106800", Ok(())),
+        ];
+        for (command, tx, timestamp, expected) in records {
+            let record = Record::new(
+                command,
+                1,
+                tx,
+                (command == "deposit").then_some(Decimal::ONE),
+            )
+            .at(timestamp.parse().unwrap());
+            let result = engine.process(&record).map_err(|err| err.to_string());
+            assert_eq!(result, expected.map_err(String::from), "tx {}", tx);
+        }
+        assert_eq!(engine.accounts[&1].total, Decimal::new(3, 0));
+        assert_eq!(
+            engine.tx_record[&3].timestamp,
+            Some("2024-01-01T10:59:30Z".parse().unwrap())
+        );
+        assert_eq!(
+            engine.get_last_activity()[&1].to_string(),
+            "2024-01-01T11:00:00Z"
+        );
+        assert!(engine.has_timestamps());
+
+        // Records without a timestamp are not checked, and do not change the last activity
+        let mut record = Record {
+            client: 1,
+            command: "dispute".to_string(),
+            amount: None,
+            tx: 3,
+            batch: None,
+            timestamp: None,
+        };
+        record.timestamp = Some("2024-01-01T10:59:00Z".parse().unwrap());
+        assert_eq!(
+            engine.process(&record).unwrap_err().to_string(),
+            "Timestamp before referenced transaction"
+        );
+        record.timestamp = None;
+        engine.process(&record).unwrap();
+        assert_eq!(
+            engine.get_last_activity()[&1].to_string(),
+            "2024-01-01T11:00:00Z"
+        );
+    }
+
+    /// Rejects withdrawals from accounts with held funds
+    #[derive(Clone)]
+    struct HeldFunds;
+
+    impl RiskRule for HeldFunds {
+        fn name(&self) -> &'static str {
+            "held_funds"
+        }
+
+        fn evaluate(&self, record: &Record, account: Option<&Account>, _now: Moment) -> Verdict {
+            if record.command == "withdrawal"
+                && account.is_some_and(|account| !account.held.is_zero())
+            {
+                return Verdict::Catch(Action::Reject, "Funds held");
+            }
+            Verdict::Allow
+        }
+
+        fn applied(&mut self, _record: &Record, _now: Moment) {}
+
+        fn clone_box(&self) -> Box<dyn RiskRule> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_risk_rules() {
+        let risk = Risk::new()
+            .with_rule(Box::new(Velocity::new(Action::Flag, 1, Span::Records(10))))
+            .with_rule(Box::new(HeldFunds));
+        let mut engine = Engine::new().with_risk(risk);
+        let records = [
+            ("deposit", Some(Decimal::TEN), 1, true),
+            ("withdrawal", Some(Decimal::ONE), 2, true),
+            // Flagged, but applied
+            ("withdrawal", Some(Decimal::ONE), 3, true),
+            ("dispute", Some(Decimal::TWO), 1, true),
+            ("withdrawal", Some(Decimal::ONE), 4, false),
+        ];
+        for (command, amount, tx, accepted) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            assert_eq!(engine.process(&record).is_ok(), accepted, "tx {}", tx);
+        }
+        assert_eq!(engine.accounts[&1].total, Decimal::new(8, 0));
+        let alerts: Vec<_> = engine
+            .get_alerts()
+            .iter()
+            .map(|alert| (alert.tx, alert.rule, alert.action))
+            .collect();
+        assert_eq!(
+            alerts,
+            vec![
+                (3, "velocity", Action::Flag),
+                (4, "velocity", Action::Flag),
+                (4, "held_funds", Action::Reject),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_auto_lock() {
+        let mut engine = Engine::new()
+            .with_auto_lock(AutoLockPolicy {
+                max_open_disputes: Some(1),
+                ..Default::default()
+            })
+            .with_unlock(true);
+        let open_disputes = Some(LockReason::OpenDisputes);
+        let records = [
+            ("deposit", Some(Decimal::TEN), 1, true, None),
+            ("deposit", Some(Decimal::TEN), 2, true, None),
+            ("dispute", None, 1, true, None),
+            // A second open dispute locks the account
+            ("dispute", Some(Decimal::ONE), 2, true, open_disputes),
+            ("deposit", Some(Decimal::ONE), 3, false, open_disputes),
+            // Settling disputes does not unlock it, nor change why it is locked
+            ("resolve", None, 2, true, open_disputes),
+            ("unlock", None, 0, true, None),
+            ("dispute", Some(Decimal::ONE), 2, true, open_disputes),
+            // A chargeback becomes the reason, and is never cleared by a record
+            ("chargeback", None, 1, true, Some(LockReason::Chargeback)),
+            ("unlock", None, 0, false, Some(LockReason::Chargeback)),
+        ];
+        for (command, amount, tx, accepted, reason) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            assert_eq!(
+                engine.process(&record).is_ok(),
+                accepted,
+                "{} {}",
+                command,
+                tx
+            );
+            assert_eq!(engine.get_lock_reasons().get(&1).copied(), reason);
+            assert_eq!(engine.accounts[&1].locked, reason.is_some());
+            assert!(engine.audit().is_empty());
+        }
+        let stats = engine.dispute_stats[&1];
+        assert_eq!((stats.open_disputes, stats.disputed), (1, Decimal::ONE));
+
+        // Locked without a reason
+        engine.lock_reasons.clear();
+        assert!(engine.audit_client(1).is_err());
+    }
+
+    #[test]
+    fn test_unlock() {
+        let records = [
+            ("deposit", Some(Decimal::TEN), 1),
+            ("dispute", None, 1),
+            ("chargeback", None, 1),
+        ];
+        let mut engine = Engine::new().with_unlock(true);
+        for (command, amount, tx) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            engine.process(&record).unwrap();
+        }
+
+        // A chargeback lock is never cleared by a record, and unlocking is opt-in
+        let unlock = Record {
+            client: 1,
+            command: "unlock".to_string(),
+            amount: None,
+            tx: 0,
+            batch: None,
+            timestamp: None,
+        };
+        let err = engine.process(&unlock).unwrap_err();
+        assert_eq!(err.to_string(), "Account locked by a chargeback");
+        assert!(engine.accounts[&1].locked);
+        let err = Engine::new().process(&unlock).unwrap_err();
+        assert_eq!(err.to_string(), "Unlock not allowed");
+    }
+
+    /// Records every event as a line of text
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn push(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl EngineObserver for Recorder {
+        fn on_operation(
+            &self,
+            tx: TxId,
+            operation: Operation,
+            amount: Decimal,
+            before: &Account,
+            after: &Account,
+        ) {
+            self.push(format!(
+                "{} {} {}: {} -> {}",
+                operation, tx, amount, before.available, after.available
+            ));
+        }
+
+        fn on_rejection(&self, record: &Record, err: &anyhow::Error) {
+            self.push(format!(
+                "rejected {} {}: {}",
+                record.command, record.tx, err
+            ));
+        }
+
+        fn on_account_created(&self, account: &Account) {
+            self.push(format!("created {}", account.id));
+        }
+
+        fn on_lock(&self, before: &Account, after: &Account, reason: LockReason) {
+            self.push(format!(
+                "locked {} {} -> {}: {}",
+                after.id, before.locked, after.locked, reason
+            ));
+        }
+
+        fn on_unlock(&self, _before: &Account, after: &Account) {
+            self.push(format!("unlocked {}", after.id));
+        }
+
+        fn on_dispute_change(
+            &self,
+            _client_id: ClientId,
+            tx: TxId,
+            before: DisputeState,
+            after: DisputeState,
+        ) {
+            self.push(format!("dispute {}: {:?} -> {:?}", tx, before, after));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let recorder = Arc::new(Recorder::default());
+        let mut engine = Engine::new().with_observer(recorder.clone());
+        let records = [
+            ("deposit", Some(Decimal::TEN), 1),
+            ("withdrawal", Some(Decimal::new(20, 0)), 2),
+            ("dispute", Some(Decimal::new(4, 0)), 1),
+            ("resolve", Some(Decimal::ONE), 1),
+            ("chargeback", None, 1),
+            ("unlock", None, 0),
+        ];
+        for (command, amount, tx) in records {
+            let record = Record {
+                client: 1,
+                command: command.to_string(),
+                amount,
+                tx,
+                batch: None,
+                timestamp: None,
+            };
+            let _ = engine.process(&record);
+        }
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(
+            *events,
+            vec![
+                "created 1",
+                "deposit 1 10: 0 -> 10",
+                "rejected withdrawal 2: Insufficient funds",
+                "dispute 1 4: 10 -> 6",
+                "dispute 1: Closed -> Open(4)",
+                "resolve 1 1: 6 -> 7",
+                "dispute 1: Open(4) -> Open(3)",
+                "chargeback 1 3: 7 -> 7",
+                "locked 1 false -> true: chargeback",
+                "dispute 1: Open(3) -> Closed",
+                "rejected unlock 0: Unlock not allowed",
+            ]
+        );
+    }
+
+    // A rolled back batch is only observed as the rejection of its culprit
+    #[test]
+    fn test_observer_batch() {
+        let recorder = Arc::new(Recorder::default());
+        let mut engine = Engine::new().with_observer(recorder.clone());
+        let record = |command: &str, amount, tx| Record::new(command, 1, tx, amount).in_batch(1);
+        let batch = [
+            record("deposit", Some(Decimal::TEN), 1),
+            record("withdrawal", Some(Decimal::new(20, 0)), 2),
+        ];
+        assert!(engine.process_batch(&batch).is_err());
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec!["rejected withdrawal 2: Insufficient funds"]
+        );
+
+        let batch = [
+            record("deposit", Some(Decimal::TEN), 1),
+            record("withdrawal", Some(Decimal::ONE), 2),
+        ];
+        engine.process_batch(&batch).unwrap();
+        assert_eq!(
+            recorder.events.lock().unwrap()[1..],
+            [
+                "created 1",
+                "deposit 1 10: 0 -> 10",
+                "withdrawal 2 1: 10 -> 9",
+            ]
+        );
+    }
 }
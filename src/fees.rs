@@ -0,0 +1,205 @@
+use crate::account::Operation;
+use crate::ids::ClientId;
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::io::Read;
+
+/// Fee charged for an operation: a flat amount plus a percentage of the operation's amount,
+/// bounded by `min` and `max`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Fee {
+    pub flat: Decimal,
+    pub percent: Decimal,
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+}
+
+impl Fee {
+    /// Fee for an operation of `amount`, rounded to 4 decimal places (Bankers rounding)
+    pub fn amount(&self, amount: Decimal) -> Result<Decimal> {
+        let overflow = || anyhow!("Overflow");
+        let variable = amount
+            .checked_mul(self.percent)
+            .ok_or_else(overflow)?
+            .checked_div(Decimal::ONE_HUNDRED)
+            .ok_or_else(overflow)?;
+        let mut fee = self.flat.checked_add(variable).ok_or_else(overflow)?;
+        if let Some(min) = self.min {
+            fee = fee.max(min);
+        }
+        if let Some(max) = self.max {
+            fee = fee.min(max);
+        }
+        Ok(fee.round_dp(4))
+    }
+}
+
+/// A row of the fees file
+#[derive(Debug, Deserialize)]
+struct FeeRow {
+    #[serde(rename = "type")]
+    command: String,
+    flat: Option<Decimal>,
+    percent: Option<Decimal>,
+    min: Option<Decimal>,
+    max: Option<Decimal>,
+}
+
+/// Fees charged per operation, credited to the `house` account.
+///
+/// The house account must not be a client: records targeting it are rejected.
+#[derive(Clone, Debug, Default)]
+pub struct FeeSchedule {
+    pub withdrawal: Option<Fee>,
+    pub chargeback: Option<Fee>,
+    pub house: Option<ClientId>,
+}
+
+impl FeeSchedule {
+    /// Create a schedule with no fees, crediting `house`
+    pub fn new(house: ClientId) -> Self {
+        Self {
+            house: Some(house),
+            ..Default::default()
+        }
+    }
+
+    /// Whether `client_id` is the house account
+    pub fn is_house(&self, client_id: ClientId) -> bool {
+        self.house == Some(client_id)
+    }
+
+    /// Read fees from CSV, one row per transaction type (`withdrawal` or `chargeback`).
+    /// Empty columns mean no flat amount, no percentage or no bound.
+    pub fn from_reader<R: Read>(mut rdr: csv::Reader<R>, house: ClientId) -> Result<Self> {
+        let mut schedule = Self::new(house);
+        for row in rdr.deserialize() {
+            let row: FeeRow = row?;
+            let fee = Fee {
+                flat: row.flat.unwrap_or_default(),
+                percent: row.percent.unwrap_or_default(),
+                min: row.min,
+                max: row.max,
+            };
+            let values = [Some(fee.flat), Some(fee.percent), fee.min, fee.max];
+            if values
+                .into_iter()
+                .flatten()
+                .any(|value| value.is_sign_negative())
+            {
+                return Err(anyhow!("Fees must be non-negative"));
+            }
+            if fee.min.zip(fee.max).is_some_and(|(min, max)| min > max) {
+                return Err(anyhow!("Minimum fee above maximum fee"));
+            }
+            match row.command.as_str() {
+                "withdrawal" => schedule.withdrawal = Some(fee),
+                "chargeback" => schedule.chargeback = Some(fee),
+                _ => return Err(anyhow!("No fees for {}", row.command)),
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Fee for `operation` of `amount` on `client_id`'s account, zero if there is none.
+    ///
+    /// The house account never pays fees to itself.
+    pub fn fee(
+        &self,
+        client_id: ClientId,
+        operation: Operation,
+        amount: Decimal,
+    ) -> Result<Decimal> {
+        let fee = match operation {
+            Operation::Withdraw => self.withdrawal,
+            Operation::Chargeback => self.chargeback,
+            _ => None,
+        };
+        match fee {
+            Some(fee) if !self.is_house(client_id) => fee.amount(amount),
+            _ => Ok(Decimal::ZERO),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::CsvReaderBuilder;
+
+    #[test]
+    fn test_fee_amount() {
+        let fee = Fee {
+            flat: Decimal::ONE,
+            percent: Decimal::new(15, 1),
+            min: None,
+            max: None,
+        };
+        assert_eq!(
+            fee.amount(Decimal::ONE_HUNDRED).unwrap(),
+            Decimal::new(25, 1)
+        );
+        assert_eq!(
+            fee.amount(Decimal::new(1, 4)).unwrap(),
+            Decimal::new(10000, 4)
+        );
+
+        let fee = Fee {
+            percent: Decimal::ONE,
+            min: Some(Decimal::TWO),
+            max: Some(Decimal::TEN),
+            ..Default::default()
+        };
+        assert_eq!(fee.amount(Decimal::ONE_HUNDRED).unwrap(), Decimal::TWO);
+        assert_eq!(
+            fee.amount(Decimal::new(500, 0)).unwrap(),
+            Decimal::new(5, 0)
+        );
+        assert_eq!(fee.amount(Decimal::new(5000, 0)).unwrap(), Decimal::TEN);
+        assert!(Fee {
+            percent: Decimal::ONE_HUNDRED,
+            ..Default::default()
+        }
+        .amount(Decimal::MAX)
+        .is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let data = "type,flat,percent,min,max\n\
+                    withdrawal,0.5,,,\n\
+                    chargeback,15,1,,25\n";
+        let rdr = CsvReaderBuilder::new(data.as_bytes()).build();
+        let schedule = FeeSchedule::from_reader(rdr, 9).unwrap();
+        assert_eq!(
+            schedule.fee(1, Operation::Withdraw, Decimal::TEN).unwrap(),
+            Decimal::new(5, 1)
+        );
+        assert_eq!(
+            schedule
+                .fee(1, Operation::Chargeback, Decimal::new(2000, 0))
+                .unwrap(),
+            Decimal::new(25, 0)
+        );
+        assert!(schedule
+            .fee(1, Operation::Deposit, Decimal::TEN)
+            .unwrap()
+            .is_zero());
+        assert!(schedule
+            .fee(9, Operation::Withdraw, Decimal::TEN)
+            .unwrap()
+            .is_zero());
+        assert!(schedule.is_house(9));
+        assert!(!FeeSchedule::default().is_house(0));
+
+        let rdr = CsvReaderBuilder::new("type,flat\ndeposit,1\n".as_bytes()).build();
+        assert!(FeeSchedule::from_reader(rdr, 9).is_err());
+        let rdr = CsvReaderBuilder::new("type,flat\nwithdrawal,-1\n".as_bytes()).build();
+        assert!(FeeSchedule::from_reader(rdr, 9).is_err());
+        let rdr = CsvReaderBuilder::new("type,min,max\nwithdrawal,2,1\n".as_bytes()).build();
+        assert!(FeeSchedule::from_reader(rdr, 9).is_err());
+        let rdr = CsvReaderBuilder::new("type,min,max\nwithdrawal,1,1\n".as_bytes()).build();
+        assert!(FeeSchedule::from_reader(rdr, 9).is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::account::{Account, Operation};
+use crate::ids::{ClientId, TxId};
+use crate::timestamp::Timestamp;
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Serialize a value through its `Display` implementation
+fn serialize_display<T: std::fmt::Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// An operation applied to a client's account, with the resulting balances
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub tx: TxId,
+    #[serde(rename = "type", serialize_with = "serialize_display")]
+    pub operation: Operation,
+    pub amount: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    /// Timestamp of the record the operation comes from, if it had one
+    pub timestamp: Option<Timestamp>,
+    /// Input line of the reversal record, on both the reversed transaction and the reversal itself
+    pub reversal: Option<u64>,
+}
+
+/// Per-client history of the operations applied, in the order they were applied
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    entries: HashMap<ClientId, Vec<HistoryEntry>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a successful `operation`, `account` being the client's account right after it
+    pub fn record(
+        &mut self,
+        tx: TxId,
+        operation: Operation,
+        amount: Decimal,
+        account: &Account,
+        timestamp: Option<Timestamp>,
+    ) {
+        self.entries
+            .entry(account.id)
+            .or_default()
+            .push(HistoryEntry {
+                tx,
+                operation,
+                amount,
+                available: account.available,
+                held: account.held,
+                total: account.total,
+                locked: account.locked,
+                timestamp,
+                reversal: None,
+            });
+    }
+
+    /// Link transaction `tx` of the client and its reversal, the record at input line `line`
+    pub fn link_reversal(&mut self, client_id: ClientId, tx: TxId, line: u64) {
+        let entries = self.entries.get_mut(&client_id).into_iter().flatten();
+        for entry in entries.filter(|entry| entry.tx == tx) {
+            if matches!(
+                entry.operation,
+                Operation::Deposit
+                    | Operation::Withdraw
+                    | Operation::ReverseDeposit
+                    | Operation::ReverseWithdrawal
+            ) {
+                entry.reversal = Some(line);
+            }
+        }
+    }
+
+    /// Client's entries referring to a tx in `range`, i.e. the running balance over that range
+    pub fn statement(
+        &self,
+        client_id: ClientId,
+        range: RangeInclusive<TxId>,
+    ) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries
+            .get(&client_id)
+            .into_iter()
+            .flatten()
+            .filter(move |entry| range.contains(&entry.tx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::AccountPolicy;
+    use crate::csv::CsvWriterBuilder;
+
+    fn history() -> History {
+        let policy = AccountPolicy::default();
+        let mut history = History::new();
+        let mut account = Account::new(1);
+        let operations = [
+            (1, Operation::Deposit, Decimal::TEN),
+            (2, Operation::Withdraw, Decimal::ONE),
+            (1, Operation::Dispute, Decimal::TWO),
+            (3, Operation::Deposit, Decimal::ONE),
+        ];
+        for (tx, operation, amount) in operations {
+            account.execute(operation, amount, &policy).unwrap();
+            let timestamp = (tx == 2).then(|| "2024-01-01T10:00:00Z".parse().unwrap());
+            history.record(tx, operation, amount, &account, timestamp);
+        }
+        history.record(4, Operation::Deposit, Decimal::ONE, &Account::new(2), None);
+        account
+            .execute(Operation::ReverseWithdrawal, Decimal::ONE, &policy)
+            .unwrap();
+        history.record(
+            2,
+            Operation::ReverseWithdrawal,
+            Decimal::ONE,
+            &account,
+            None,
+        );
+        history.link_reversal(1, 2, 6);
+        history
+    }
+
+    #[test]
+    fn test_statement() {
+        let history = history();
+        let statement: Vec<_> = history.statement(1, 0..=TxId::MAX).collect();
+        assert_eq!(statement.len(), 5);
+        assert_eq!(statement[2].operation, Operation::Dispute);
+        assert_eq!(statement[2].available, Decimal::new(7, 0));
+        assert_eq!(statement[2].held, Decimal::TWO);
+        assert_eq!(statement[3].total, Decimal::TEN);
+
+        let statement: Vec<_> = history.statement(1, 2..=3).map(|entry| entry.tx).collect();
+        assert_eq!(statement, vec![2, 3, 2]);
+        assert_eq!(history.statement(3, 0..=TxId::MAX).count(), 0);
+    }
+
+    #[test]
+    fn test_statement_csv() {
+        let history = history();
+        let mut wtr = CsvWriterBuilder::new(vec![]).build();
+        for entry in history.statement(1, 1..=2) {
+            wtr.serialize(entry).unwrap();
+        }
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            "tx,type,amount,available,held,total,locked,timestamp,reversal\n\
+             1,deposit,10,10,0,10,false,,\n\
+             2,withdrawal,1,9,0,9,false,2024-01-01T10:00:00Z,6\n\
+             1,dispute,2,7,2,9,false,,\n\
+             2,reversal,1,9,2,11,false,,6\n"
+        );
+    }
+
+    #[test]
+    fn test_statement_json() {
+        let history = history();
+        let statement: Vec<_> = history.statement(1, 2..=2).take(1).collect();
+        let data = serde_json::to_string(&statement).unwrap();
+        assert_eq!(
+            data,
+            r#"[{"tx":2,"type":"withdrawal","amount":"1","available":"9","held":"0","total":"9","locked":false,"timestamp":"2024-01-01T10:00:00Z","reversal":6}]"#
+        );
+    }
+}
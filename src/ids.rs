@@ -0,0 +1,5 @@
+/// Identifier of a client, and of its account
+pub type ClientId = u64;
+
+/// Identifier of a transaction
+pub type TxId = u64;
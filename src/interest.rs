@@ -0,0 +1,132 @@
+use anyhow::{anyhow, Result};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::fmt;
+use std::str::FromStr;
+
+/// Day-count convention, i.e. how many days make up a year of interest.
+///
+/// Conventions looking at calendar dates (30/360, Actual/Actual) are not supported, as accruals
+/// only know the number of days elapsed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DayCount {
+    /// Actual days over a 360 days year
+    Actual360,
+    /// Actual days over a 365 days year, leap or not
+    #[default]
+    Actual365,
+}
+
+impl DayCount {
+    /// Days in a year
+    pub fn basis(&self) -> Decimal {
+        match self {
+            DayCount::Actual360 => Decimal::from(360),
+            DayCount::Actual365 => Decimal::from(365),
+        }
+    }
+}
+
+impl FromStr for DayCount {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "act/360" => Ok(DayCount::Actual360),
+            "act/365" => Ok(DayCount::Actual365),
+            _ => Err(anyhow!("Unknown day-count convention {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for DayCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DayCount::Actual360 => f.write_str("act/360"),
+            DayCount::Actual365 => f.write_str("act/365"),
+        }
+    }
+}
+
+/// Simple interest credited on available funds
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InterestPolicy {
+    /// Yearly rate, as a percentage
+    pub rate: Decimal,
+    pub day_count: DayCount,
+}
+
+impl InterestPolicy {
+    /// Interest earned by `balance` over `days`, rounded to 4 decimal places (Bankers rounding).
+    ///
+    /// Negative balances (i.e. overdrafts) earn nothing.
+    pub fn interest(&self, balance: Decimal, days: Decimal) -> Result<Decimal> {
+        if balance <= Decimal::ZERO {
+            return Ok(Decimal::ZERO);
+        }
+        let overflow = || anyhow!("Overflow");
+        let interest = balance
+            .checked_mul(self.rate)
+            .ok_or_else(overflow)?
+            .checked_mul(days)
+            .ok_or_else(overflow)?
+            .checked_div(Decimal::ONE_HUNDRED * self.day_count.basis())
+            .ok_or_else(overflow)?;
+        Ok(interest.round_dp_with_strategy(4, RoundingStrategy::MidpointNearestEven))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_day_count() {
+        assert_eq!("act/360".parse::<DayCount>().unwrap(), DayCount::Actual360);
+        assert_eq!("act/365".parse::<DayCount>().unwrap(), DayCount::Actual365);
+        assert!("30/360".parse::<DayCount>().is_err());
+        assert_eq!(DayCount::Actual360.to_string(), "act/360");
+    }
+
+    #[test]
+    fn test_interest() {
+        let policy = InterestPolicy {
+            rate: Decimal::new(365, 2),
+            day_count: DayCount::Actual365,
+        };
+        // 3.65% a year is 0.01% a day
+        assert_eq!(
+            policy
+                .interest(Decimal::new(10000, 0), Decimal::from(30))
+                .unwrap(),
+            Decimal::new(30, 0)
+        );
+        let policy = InterestPolicy {
+            day_count: DayCount::Actual360,
+            ..policy
+        };
+        assert_eq!(
+            policy
+                .interest(Decimal::new(10000, 0), Decimal::from(30))
+                .unwrap(),
+            Decimal::new(304167, 4)
+        );
+        // 3.6% a year is 0.01% a day, and midpoints are rounded to even
+        let policy = InterestPolicy {
+            rate: Decimal::new(36, 1),
+            ..policy
+        };
+        assert_eq!(
+            policy.interest(Decimal::new(5, 1), Decimal::ONE).unwrap(),
+            Decimal::ZERO
+        );
+        assert_eq!(
+            policy.interest(Decimal::new(15, 1), Decimal::ONE).unwrap(),
+            Decimal::new(2, 4)
+        );
+        assert!(policy
+            .interest(-Decimal::ONE_HUNDRED, Decimal::ONE)
+            .unwrap()
+            .is_zero());
+        assert!(policy.interest(Decimal::MAX, Decimal::MAX).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::account::{Account, Operation};
+use crate::ids::{ClientId, TxId};
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A ledger bucket money can be moved from or to
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Bucket {
+    /// Client's available funds
+    Available(ClientId),
+    /// Client's held funds
+    Held(ClientId),
+    /// Where deposits come from and withdrawals go to
+    ExternalFunding,
+    /// Where charged back funds go to
+    ChargebackLoss,
+    /// Fees paid by clients, until credited to the house account
+    Fees,
+    /// Where interest paid to clients comes from
+    InterestExpense,
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bucket::Available(client) => write!(f, "client:{}:available", client),
+            Bucket::Held(client) => write!(f, "client:{}:held", client),
+            Bucket::ExternalFunding => write!(f, "external_funding"),
+            Bucket::ChargebackLoss => write!(f, "chargeback_loss"),
+            Bucket::Fees => write!(f, "fees"),
+            Bucket::InterestExpense => write!(f, "interest_expense"),
+        }
+    }
+}
+
+impl Serialize for Bucket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A journal entry, moving `amount` out of the `debit` bucket and into the `credit` one.
+///
+/// Having a single debit and a single credit of the same amount, every entry is balanced by construction.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Entry {
+    pub tx: TxId,
+    pub debit: Bucket,
+    pub credit: Bucket,
+    pub amount: Decimal,
+    /// Input line of the record the entry comes from, if it is a reversal
+    pub reversal: Option<u64>,
+}
+
+/// Double-entry journal of every operation run on accounts.
+///
+/// A bucket's balance is the sum of its credits minus the sum of its debits, so the balances of all
+/// the buckets always sum up to zero, and the client's buckets must match the `Account` balances.
+#[derive(Clone, Debug, Default)]
+pub struct Journal {
+    entries: Vec<Entry>,
+    balances: HashMap<Bucket, Decimal>,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Post the entry for a successful `operation` on the client's account
+    pub fn post(&mut self, tx: TxId, client_id: ClientId, operation: Operation, amount: Decimal) {
+        let (debit, credit) = match operation {
+            Operation::Deposit => (Bucket::ExternalFunding, Bucket::Available(client_id)),
+            Operation::Withdraw => (Bucket::Available(client_id), Bucket::ExternalFunding),
+            Operation::Dispute => (Bucket::Available(client_id), Bucket::Held(client_id)),
+            Operation::Resolve => (Bucket::Held(client_id), Bucket::Available(client_id)),
+            Operation::Chargeback => (Bucket::Held(client_id), Bucket::ChargebackLoss),
+            Operation::ReverseDeposit => (Bucket::Available(client_id), Bucket::ExternalFunding),
+            Operation::ReverseWithdrawal => (Bucket::ExternalFunding, Bucket::Available(client_id)),
+            Operation::Authorize => (Bucket::Available(client_id), Bucket::Held(client_id)),
+            Operation::Capture => (Bucket::Held(client_id), Bucket::ExternalFunding),
+            Operation::Release => (Bucket::Held(client_id), Bucket::Available(client_id)),
+            Operation::Fee => (Bucket::Available(client_id), Bucket::Fees),
+            Operation::FeeIncome => (Bucket::Fees, Bucket::Available(client_id)),
+            Operation::Interest => (Bucket::InterestExpense, Bucket::Available(client_id)),
+        };
+        // Balances saturate instead of overflowing: a saturated balance will not verify
+        let balance = self.balances.entry(debit).or_default();
+        *balance = balance.saturating_sub(amount);
+        let balance = self.balances.entry(credit).or_default();
+        *balance = balance.saturating_add(amount);
+        self.entries.push(Entry {
+            tx,
+            debit,
+            credit,
+            amount,
+            reversal: None,
+        });
+    }
+
+    /// Post the entry for a successful reversal `operation`, the reversal being the record at input
+    /// line `line`
+    pub fn post_reversal(
+        &mut self,
+        tx: TxId,
+        client_id: ClientId,
+        operation: Operation,
+        amount: Decimal,
+        line: u64,
+    ) {
+        self.post(tx, client_id, operation, amount);
+        if let Some(entry) = self.entries.last_mut() {
+            entry.reversal = Some(line);
+        }
+    }
+
+    /// Balance of `bucket`, derived from the entries posted so far
+    pub fn balance(&self, bucket: Bucket) -> Decimal {
+        self.balances.get(&bucket).copied().unwrap_or_default()
+    }
+
+    /// All the entries posted so far, in order
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Verify `account` balances against the ones derived from the journal
+    pub fn verify_account(&self, account: &Account) -> Result<()> {
+        if self.balance(Bucket::Available(account.id)) != account.available {
+            return Err(anyhow!("Available funds do not match the journal"));
+        }
+        if self.balance(Bucket::Held(account.id)) != account.held {
+            return Err(anyhow!("Held funds do not match the journal"));
+        }
+        Ok(())
+    }
+
+    /// Verify the journal is balanced, i.e. money was only moved between buckets
+    pub fn verify(&self) -> Result<()> {
+        let sum = self
+            .balances
+            .values()
+            .try_fold(Decimal::ZERO, |sum, balance| sum.checked_add(*balance));
+        if sum != Some(Decimal::ZERO) {
+            return Err(anyhow!("Journal is not balanced"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::CsvWriterBuilder;
+
+    #[test]
+    fn test_post() {
+        let mut journal = Journal::new();
+        journal.post(1, 1, Operation::Deposit, Decimal::TEN);
+        journal.post(2, 1, Operation::Withdraw, Decimal::ONE);
+        journal.post(1, 1, Operation::Dispute, Decimal::new(4, 0));
+        journal.post(1, 1, Operation::Resolve, Decimal::ONE);
+        journal.post(1, 1, Operation::Chargeback, Decimal::TWO);
+        assert_eq!(journal.entries().len(), 5);
+        assert_eq!(journal.balance(Bucket::Available(1)), Decimal::new(6, 0));
+        assert_eq!(journal.balance(Bucket::Held(1)), Decimal::ONE);
+        assert_eq!(
+            journal.balance(Bucket::ExternalFunding),
+            Decimal::new(-9, 0)
+        );
+        assert_eq!(journal.balance(Bucket::ChargebackLoss), Decimal::TWO);
+        assert_eq!(journal.balance(Bucket::Available(2)), Decimal::ZERO);
+        assert!(journal.verify().is_ok());
+    }
+
+    #[test]
+    fn test_post_authorization() {
+        let mut journal = Journal::new();
+        journal.post(1, 1, Operation::Deposit, Decimal::TEN);
+        journal.post(2, 1, Operation::Authorize, Decimal::new(4, 0));
+        journal.post(2, 1, Operation::Capture, Decimal::ONE);
+        journal.post(2, 1, Operation::Release, Decimal::TWO);
+        assert_eq!(journal.balance(Bucket::Available(1)), Decimal::new(8, 0));
+        assert_eq!(journal.balance(Bucket::Held(1)), Decimal::ONE);
+        assert_eq!(
+            journal.balance(Bucket::ExternalFunding),
+            Decimal::new(-9, 0)
+        );
+        assert!(journal.verify().is_ok());
+    }
+
+    #[test]
+    fn test_post_fee() {
+        let mut journal = Journal::new();
+        journal.post(1, 1, Operation::Deposit, Decimal::TEN);
+        journal.post(2, 1, Operation::Withdraw, Decimal::TWO);
+        journal.post(2, 1, Operation::Fee, Decimal::ONE);
+        journal.post(2, 9, Operation::FeeIncome, Decimal::ONE);
+        assert_eq!(journal.balance(Bucket::Available(1)), Decimal::new(7, 0));
+        assert_eq!(journal.balance(Bucket::Available(9)), Decimal::ONE);
+        assert_eq!(journal.balance(Bucket::Fees), Decimal::ZERO);
+        assert!(journal.verify().is_ok());
+        journal.post(3, 1, Operation::Interest, Decimal::ONE);
+        assert_eq!(journal.balance(Bucket::Available(1)), Decimal::new(8, 0));
+        assert_eq!(journal.balance(Bucket::InterestExpense), -Decimal::ONE);
+        assert!(journal.verify().is_ok());
+    }
+
+    #[test]
+    fn test_verify_account() {
+        let mut journal = Journal::new();
+        journal.post(1, 1, Operation::Deposit, Decimal::TWO);
+        journal.post(1, 1, Operation::Dispute, Decimal::ONE);
+        let mut account = Account {
+            id: 1,
+            locked: false,
+            total: Decimal::TWO,
+            available: Decimal::ONE,
+            held: Decimal::ONE,
+            overdraft: Decimal::ZERO,
+        };
+        assert!(journal.verify_account(&account).is_ok());
+        account.available = Decimal::TWO;
+        assert!(journal.verify_account(&account).is_err());
+        account.available = Decimal::ONE;
+        account.held = Decimal::ZERO;
+        assert!(journal.verify_account(&account).is_err());
+    }
+
+    #[test]
+    fn test_verify_unbalanced() {
+        let mut journal = Journal::new();
+        journal.post(1, 1, Operation::Deposit, Decimal::TWO);
+        assert!(journal.verify().is_ok());
+        journal
+            .balances
+            .insert(Bucket::ChargebackLoss, Decimal::ONE);
+        assert!(journal.verify().is_err());
+    }
+
+    #[test]
+    fn test_export() {
+        let mut journal = Journal::new();
+        journal.post(1, 3, Operation::Deposit, Decimal::TWO);
+        journal.post(1, 3, Operation::Dispute, Decimal::ONE);
+        journal.post(1, 3, Operation::Chargeback, Decimal::ONE);
+        journal.post(2, 3, Operation::Withdraw, Decimal::ONE);
+        journal.post_reversal(2, 3, Operation::ReverseWithdrawal, Decimal::ONE, 5);
+        let mut wtr = CsvWriterBuilder::new(vec![]).build();
+        for entry in journal.entries() {
+            wtr.serialize(entry).unwrap();
+        }
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            "tx,debit,credit,amount,reversal\n\
+             1,external_funding,client:3:available,2,\n\
+             1,client:3:available,client:3:held,1,\n\
+             1,client:3:held,chargeback_loss,1,\n\
+             2,client:3:available,external_funding,1,\n\
+             2,external_funding,client:3:available,1,5\n"
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use crate::account::{Account, Operation};
+use crate::ids::{ClientId, TxId};
+use crate::window::{Moment, Span};
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+
+/// Compliance limits of a single account, `None` meaning unlimited
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AccountLimits {
+    /// Largest single withdrawal
+    pub max_withdrawal: Option<Decimal>,
+    /// Largest sum of the withdrawals within `window`
+    pub max_window_withdrawal: Option<Decimal>,
+    /// Size of the rolling window, in the client's records or in time. All withdrawals count if `None`
+    pub window: Option<Span>,
+    /// Largest total the account can reach by being credited
+    pub max_balance: Option<Decimal>,
+    /// Approved overdraft, see `Account::overdraft`
+    pub overdraft: Option<Decimal>,
+}
+
+/// A row of the limits file. Without a client, the row holds the default limits.
+#[derive(Debug, Deserialize)]
+struct LimitsRow {
+    client: Option<ClientId>,
+    max_withdrawal: Option<Decimal>,
+    max_window_withdrawal: Option<Decimal>,
+    window: Option<Span>,
+    max_balance: Option<Decimal>,
+    overdraft: Option<Decimal>,
+}
+
+/// Withdrawals of an account within the rolling window, reversed ones excluded
+#[derive(Clone, Debug, Default)]
+struct Window {
+    withdrawals: VecDeque<(Moment, TxId, Decimal)>,
+    total: Decimal,
+}
+
+impl Window {
+    /// Forget the withdrawals no longer in a window of `size`, as of `now`
+    fn slide(&mut self, size: Option<Span>, now: Moment) {
+        let Some(size) = size else {
+            return;
+        };
+        while let Some(&(at, _, amount)) = self.withdrawals.front() {
+            if size.contains(at, now) {
+                break;
+            }
+            self.withdrawals.pop_front();
+            self.total -= amount;
+        }
+    }
+}
+
+/// Compliance limits of every account, keeping track of the withdrawals within their windows.
+///
+/// Accounts without limits of their own get the default ones.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    default: AccountLimits,
+    clients: HashMap<ClientId, AccountLimits>,
+    windows: HashMap<ClientId, Window>,
+}
+
+impl Limits {
+    /// Create limits applying `default` to every account
+    #[allow(dead_code)]
+    pub fn new(default: AccountLimits) -> Self {
+        Self {
+            default,
+            ..Default::default()
+        }
+    }
+
+    /// Set the limits of a single client, replacing the default ones
+    pub fn with_client(mut self, client_id: ClientId, limits: AccountLimits) -> Self {
+        self.clients.insert(client_id, limits);
+        self
+    }
+
+    /// Read limits from CSV, one row per client plus an optional one without client for the default
+    /// limits. Empty columns mean no limit.
+    pub fn from_reader<R: Read>(mut rdr: csv::Reader<R>) -> Result<Self> {
+        let mut limits = Self::default();
+        for row in rdr.deserialize() {
+            let row: LimitsRow = row?;
+            if row
+                .overdraft
+                .is_some_and(|overdraft| overdraft.is_sign_negative())
+            {
+                return Err(anyhow!("Overdraft must be non-negative"));
+            }
+            let account_limits = AccountLimits {
+                max_withdrawal: row.max_withdrawal,
+                max_window_withdrawal: row.max_window_withdrawal,
+                window: row.window,
+                max_balance: row.max_balance,
+                overdraft: row.overdraft,
+            };
+            match row.client {
+                Some(client_id) => limits = limits.with_client(client_id, account_limits),
+                None => limits.default = account_limits,
+            }
+        }
+        Ok(limits)
+    }
+
+    /// Limits of a client's account
+    pub fn get(&self, client_id: ClientId) -> &AccountLimits {
+        self.clients.get(&client_id).unwrap_or(&self.default)
+    }
+
+    /// Whether any account gets an overdraft
+    pub fn has_overdrafts(&self) -> bool {
+        std::iter::once(&self.default)
+            .chain(self.clients.values())
+            .any(|limits| {
+                limits
+                    .overdraft
+                    .is_some_and(|overdraft| !overdraft.is_zero())
+            })
+    }
+
+    /// Check `operation` would keep `account` within its withdrawal limits, `now` being the current
+    /// moment of the client. The balance limit is checked on the outcome, see `check_balance`.
+    pub fn check(
+        &mut self,
+        account: &Account,
+        operation: Operation,
+        amount: Decimal,
+        now: Moment,
+    ) -> Result<()> {
+        if operation != Operation::Withdraw {
+            return Ok(());
+        }
+        let limits = *self.get(account.id);
+        if limits.max_withdrawal.is_some_and(|max| amount > max) {
+            return Err(anyhow!("Withdrawal limit exceeded"));
+        }
+        if let Some(max) = limits.max_window_withdrawal {
+            let window = self.windows.entry(account.id).or_default();
+            window.slide(limits.window, now);
+            if window.total.checked_add(amount).is_none_or(|sum| sum > max) {
+                return Err(anyhow!("Window withdrawal limit exceeded"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check an operation taking an account from `before` to `after` keeps it within its balance
+    /// limit.
+    ///
+    /// Whatever credits the account counts: deposits, reversed withdrawals, interest or fee income.
+    pub fn check_balance(&self, before: &Account, after: &Account) -> Result<()> {
+        let max_balance = self.get(after.id).max_balance;
+        if after.total > before.total && max_balance.is_some_and(|max| after.total > max) {
+            return Err(anyhow!("Balance limit exceeded"));
+        }
+        Ok(())
+    }
+
+    /// Account for a successful `operation` of transaction `tx`, at `now`
+    pub fn record(
+        &mut self,
+        client_id: ClientId,
+        tx: TxId,
+        operation: Operation,
+        amount: Decimal,
+        now: Moment,
+    ) {
+        if self.get(client_id).max_window_withdrawal.is_none() {
+            return;
+        }
+        let window = self.windows.entry(client_id).or_default();
+        match operation {
+            Operation::Withdraw => {
+                window.withdrawals.push_back((now, tx, amount));
+                window.total = window.total.saturating_add(amount);
+            }
+            // A reversed withdrawal no longer counts, if it is still in the window
+            Operation::ReverseWithdrawal => {
+                let index = window.withdrawals.iter().position(|(_, id, _)| *id == tx);
+                if let Some((_, _, amount)) =
+                    index.and_then(|index| window.withdrawals.remove(index))
+                {
+                    window.total -= amount;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::CsvReaderBuilder;
+
+    fn at(records: u64) -> Moment {
+        Moment {
+            records,
+            timestamp: None,
+        }
+    }
+
+    fn account(total: Decimal) -> Account {
+        Account {
+            id: 1,
+            locked: false,
+            total,
+            available: total,
+            held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_max_withdrawal_and_balance() {
+        let mut limits = Limits::new(AccountLimits {
+            max_withdrawal: Some(Decimal::TEN),
+            max_balance: Some(Decimal::ONE_HUNDRED),
+            ..Default::default()
+        });
+        let account = account(Decimal::new(95, 0));
+        assert!(limits
+            .check(&account, Operation::Withdraw, Decimal::TEN, at(1))
+            .is_ok());
+        let err = limits
+            .check(&account, Operation::Withdraw, Decimal::new(11, 0), at(1))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Withdrawal limit exceeded");
+        assert!(limits
+            .check(&account, Operation::Dispute, Decimal::ONE_HUNDRED, at(1))
+            .is_ok());
+
+        assert!(limits
+            .check_balance(&account, &self::account(Decimal::ONE_HUNDRED))
+            .is_ok());
+        let err = limits
+            .check_balance(&account, &self::account(Decimal::new(101, 0)))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Balance limit exceeded");
+        // An account already over its limit can still be debited
+        let over = self::account(Decimal::new(120, 0));
+        assert!(limits
+            .check_balance(&over, &self::account(Decimal::new(110, 0)))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_window() {
+        let mut limits = Limits::new(AccountLimits {
+            max_window_withdrawal: Some(Decimal::TEN),
+            window: Some(Span::Records(3)),
+            ..Default::default()
+        });
+        let account = account(Decimal::ONE_HUNDRED);
+        for clock in [1, 2] {
+            limits
+                .check(&account, Operation::Withdraw, Decimal::new(5, 0), at(clock))
+                .unwrap();
+            limits.record(1, clock, Operation::Withdraw, Decimal::new(5, 0), at(clock));
+        }
+        let err = limits
+            .check(&account, Operation::Withdraw, Decimal::ONE, at(3))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Window withdrawal limit exceeded");
+        // The first withdrawal left the window
+        assert!(limits
+            .check(&account, Operation::Withdraw, Decimal::new(5, 0), at(4))
+            .is_ok());
+        assert!(limits
+            .check(&account, Operation::Withdraw, Decimal::new(6, 0), at(4))
+            .is_err());
+        // Nor does the second one count once reversed
+        limits.record(
+            1,
+            2,
+            Operation::ReverseWithdrawal,
+            Decimal::new(5, 0),
+            at(4),
+        );
+        assert!(limits
+            .check(&account, Operation::Withdraw, Decimal::TEN, at(4))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let mut limits = Limits::new(AccountLimits {
+            max_window_withdrawal: Some(Decimal::TEN),
+            window: Some(Span::Secs(60)),
+            ..Default::default()
+        });
+        let account = account(Decimal::ONE_HUNDRED);
+        let at = |records, timestamp: &str| Moment {
+            records,
+            timestamp: Some(timestamp.parse().unwrap()),
+        };
+        limits.record(1, 1, Operation::Withdraw, Decimal::TEN, at(1, "1000"));
+        // However many records went by
+        assert!(limits
+            .check(&account, Operation::Withdraw, Decimal::ONE, at(100, "1059"))
+            .is_err());
+        assert!(limits
+            .check(&account, Operation::Withdraw, Decimal::TEN, at(2, "1060"))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let data = "client,max_withdrawal,max_window_withdrawal,window,max_balance,overdraft\n\
+                    ,10,,,,\n\
+                    2,,50,100,1000,20\n\
+                    3,,50,24h,,\n";
+        let rdr = CsvReaderBuilder::new(data.as_bytes()).build();
+        let limits = Limits::from_reader(rdr).unwrap();
+        assert_eq!(limits.get(1).max_withdrawal, Some(Decimal::TEN));
+        assert_eq!(
+            *limits.get(2),
+            AccountLimits {
+                max_withdrawal: None,
+                max_window_withdrawal: Some(Decimal::new(50, 0)),
+                window: Some(Span::Records(100)),
+                max_balance: Some(Decimal::new(1000, 0)),
+                overdraft: Some(Decimal::new(20, 0)),
+            }
+        );
+        assert_eq!(limits.get(3).window, Some(Span::Secs(86_400)));
+        assert!(limits.has_overdrafts());
+
+        let rdr = CsvReaderBuilder::new("client,max_withdrawal\nx,1\n".as_bytes()).build();
+        assert!(Limits::from_reader(rdr).is_err());
+        let rdr = CsvReaderBuilder::new("client,window\n1,1w\n".as_bytes()).build();
+        assert!(Limits::from_reader(rdr).is_err());
+        let rdr = CsvReaderBuilder::new("client,overdraft\n1,-1\n".as_bytes()).build();
+        assert!(Limits::from_reader(rdr).is_err());
+        let rdr = CsvReaderBuilder::new("client,overdraft\n1,0\n".as_bytes()).build();
+        assert!(!Limits::from_reader(rdr).unwrap().has_overdrafts());
+    }
+}
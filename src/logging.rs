@@ -0,0 +1,258 @@
+use crate::timestamp::Timestamp;
+use anyhow::{anyhow, Result};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use std::str::FromStr;
+
+/// Format of the log lines
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// `<timestamp> <LEVEL> <module>: <message> key=value...`
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `module`, `message` and the fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Invalid log format {}", value)),
+        }
+    }
+}
+
+/// Levels of the events to log: a default level, and the levels of some modules and their
+/// submodules, e.g. `info,webhook=debug`. `main` is the module of the command line tool itself.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut filter = Self::default();
+        for directive in value.split(',').filter(|directive| !directive.is_empty()) {
+            let parse_level = |level: &str| {
+                level
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid log level {}", level))
+            };
+            match directive.split_once('=') {
+                Some((module, level)) => filter
+                    .modules
+                    .push((module.to_string(), parse_level(level)?)),
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl LogFilter {
+    /// Level of the events logged from `target`, a module path
+    fn level(&self, target: &str) -> LevelFilter {
+        let module = match target.strip_prefix("transaction_engine") {
+            Some("") => "main",
+            Some(module) => module.trim_start_matches("::"),
+            None => target,
+        };
+        self.modules
+            .iter()
+            .filter(|(name, _)| {
+                module
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Most verbose level of any module
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+/// Key-value fields of a log event
+#[derive(Default)]
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+/// Format `record`, logged at `timestamp`, as a single line
+fn format(format: LogFormat, timestamp: Timestamp, record: &Record) -> String {
+    let mut fields = Fields::default();
+    let _ = record.key_values().visit(&mut fields);
+    let module = record.module_path().unwrap_or(record.target());
+    match format {
+        LogFormat::Text => {
+            let mut line = format!(
+                "{} {:<5} {}: {}",
+                timestamp,
+                record.level(),
+                module,
+                record.args()
+            );
+            for (key, value) in fields.0 {
+                match value {
+                    serde_json::Value::String(value)
+                        if value.is_empty() || value.contains([' ', '"', '=']) =>
+                    {
+                        line.push_str(&format!(" {}={:?}", key, value))
+                    }
+                    serde_json::Value::String(value) => {
+                        line.push_str(&format!(" {}={}", key, value))
+                    }
+                    value => line.push_str(&format!(" {}={}", key, value)),
+                }
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = serde_json::Map::new();
+            object.insert("timestamp".into(), timestamp.to_string().into());
+            object.insert("level".into(), record.level().as_str().into());
+            object.insert("module".into(), module.into());
+            object.insert("message".into(), record.args().to_string().into());
+            object.extend(fields.0);
+            serde_json::Value::Object(object).to_string()
+        }
+    }
+}
+
+/// Logger writing to `stderr`, so that logs do not interfere with the results
+struct Logger {
+    format: LogFormat,
+    filter: LogFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = format(self.format, Timestamp::now(), record);
+            let _ = writeln!(std::io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, once for the whole process
+pub fn init(format: LogFormat, filter: LogFilter) -> Result<()> {
+    log::set_max_level(filter.max_level());
+    log::set_boxed_logger(Box::new(Logger { format, filter }))
+        .map_err(|_| anyhow!("Logger already installed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_filter() {
+        let filter: LogFilter = "warn,webhook=debug,main=error,engine::audit=off"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level("transaction_engine"), LevelFilter::Error);
+        assert_eq!(
+            filter.level("transaction_engine::webhook"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            filter.level("transaction_engine::webhooks"),
+            LevelFilter::Warn
+        );
+        assert_eq!(
+            filter.level("transaction_engine::engine"),
+            LevelFilter::Warn
+        );
+        assert_eq!(
+            filter.level("transaction_engine::engine::audit"),
+            LevelFilter::Off
+        );
+        assert_eq!(filter.level("csv"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        assert_eq!("".parse::<LogFilter>().unwrap(), LogFilter::default());
+        assert!("loud".parse::<LogFilter>().is_err());
+        assert!("webhook=loud".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let timestamp: Timestamp = "2024-03-01T12:30:00Z".parse().unwrap();
+        let fields: &[(&str, Value)] = &[
+            ("line", Value::from(3_u64)),
+            ("client", Value::from(1_u64)),
+            ("error_kind", Value::from("Insufficient funds")),
+            ("type", Value::from("withdrawal")),
+        ];
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("transaction_engine")
+            .module_path(Some("transaction_engine"))
+            .args(format_args!("Record rejected"))
+            .key_values(&fields)
+            .build();
+
+        assert_eq!(
+            format(LogFormat::Text, timestamp, &record),
+            "2024-03-01T12:30:00Z WARN  transaction_engine: Record rejected line=3 client=1 \
+             error_kind=\"Insufficient funds\" type=withdrawal"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format(LogFormat::Json, timestamp, &record)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": "2024-03-01T12:30:00Z",
+                "level": "WARN",
+                "module": "transaction_engine",
+                "message": "Record rejected",
+                "line": 3,
+                "client": 1,
+                "error_kind": "Insufficient funds",
+                "type": "withdrawal",
+            })
+        );
+    }
+}
@@ -1,69 +1,547 @@
 mod account;
+mod auto_lock;
+mod batch;
+mod channel;
 mod csv;
 mod deser;
+mod dry_run;
 mod engine;
+mod fees;
+mod history;
+mod ids;
+mod interest;
+mod ledger;
+mod limits;
+mod logging;
+mod metrics;
+mod observer;
+mod options;
+mod reconcile;
+mod replay;
+mod risk;
+mod timestamp;
+mod webhook;
+mod window;
 
-use crate::deser::{OutRecord, Record};
-use anyhow::Result;
+use crate::batch::Batches;
+use crate::channel::WaitStats;
+use crate::csv::Row;
+use crate::deser::{OutRecord, Record, RejectRecord, ReplayOutRecord};
+use crate::dry_run::DryRunReport;
+use crate::engine::Engine;
+use crate::fees::FeeSchedule;
+use crate::history::History;
+use crate::ledger::Journal;
+use crate::limits::Limits;
+use crate::metrics::Metrics;
+use crate::options::{Command, Format, ReplayOptions, StatementOptions};
+use crate::reconcile::Reconciliation;
+use crate::replay::Replay;
+use crate::risk::{Alert, Risk};
+use crate::timestamp::Timestamp;
+use crate::webhook::{Notifier, Outbox};
+use anyhow::{anyhow, Result};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Write the reconciliation report as CSV to `file_path`
+fn write_reconciliation(file_path: &Path, reconciliation: &Reconciliation) -> Result<()> {
+    let mut wtr = csv::csv_writer_to_file(file_path)?;
+    for line in reconciliation.lines() {
+        wtr.serialize(line)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Write the journal entries as CSV to `file_path`
+fn write_journal(file_path: &Path, journal: &Journal) -> Result<()> {
+    let mut wtr = csv::csv_writer_to_file(file_path)?;
+    for entry in journal.entries() {
+        wtr.serialize(entry)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Write the alerts raised by risk rules as CSV to `file_path`
+fn write_alerts(file_path: &Path, alerts: &[Alert]) -> Result<()> {
+    let mut wtr = csv::csv_writer_to_file(file_path)?;
+    for alert in alerts {
+        wtr.serialize(alert)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Print all the accounts to stdout
+fn write_accounts(
+    engine: &Engine,
+    flag_negative: bool,
+    show_overdraft: bool,
+    show_interest: bool,
+    show_lock_reason: bool,
+) {
+    // retrieve accounts data
+    let accounts = engine.get_accounts();
+
+    // build CSV writer
+    let mut wtr = csv::CsvWriterBuilder::new(std::io::stdout()).build();
+
+    // Start writing
+    for account in accounts.values() {
+        let mut out_record = OutRecord::from(account);
+        if flag_negative {
+            out_record.negative = Some(account.is_negative());
+            out_record.debt = Some(account.debt());
+        }
+        if show_overdraft {
+            out_record.overdraft = Some(account.overdraft);
+            out_record.overdrawn = Some(account.overdrawn());
+        }
+        if show_interest {
+            let flows = engine.get_flows().get(&account.id);
+            out_record.interest = Some(flows.map(|flows| flows.interest).unwrap_or_default());
+        }
+        if engine.has_timestamps() {
+            let last_activity = engine.get_last_activity().get(&account.id);
+            out_record.last_activity =
+                Some(last_activity.map(ToString::to_string).unwrap_or_default());
+        }
+        if show_lock_reason {
+            let lock_reason = engine.get_lock_reasons().get(&account.id);
+            out_record.lock_reason = Some(lock_reason.map(ToString::to_string).unwrap_or_default());
+        }
+        if let Err(err) = wtr.serialize(out_record) {
+            log::error!(client = account.id, error:% = err; "Error writing account");
+        }
+    }
+    if let Err(err) = wtr.flush() {
+        log::error!(error:% = err; "Error flushing writer");
+    }
+}
+
+/// Log how often, and how long, one side of the channel between the reader and the engine waited
+fn log_wait_stats(side: &str, stats: &WaitStats) {
+    log::info!(
+        side = side,
+        chunks = stats.chunks,
+        blocked = stats.blocked,
+        waited_ms = stats.waited.as_millis() as u64;
+        "Channel stats"
+    );
+}
+
+/// Log a rejected record, with fields the log pipeline can index on
+fn log_rejection(line: usize, record: &Record, err: &anyhow::Error) {
+    log::warn!(
+        line = line,
+        "type" = record.command.as_str(),
+        client = record.client,
+        tx = record.tx,
+        error_kind:% = err.root_cause(),
+        error:% = format!("{:#}", err);
+        "Record rejected"
+    );
+}
+
+/// Write a rejected record to the rejects file, if any
+fn write_rejection(
+    rejects: &mut Option<::csv::Writer<std::fs::File>>,
+    line: usize,
+    record: &Record,
+    error: String,
+) {
+    let Some(wtr) = rejects else {
+        return;
+    };
+    if let Err(err) = wtr.serialize(RejectRecord::new(line, record, error)) {
+        log::error!(line = line, error:% = err; "Error writing rejection");
+    }
+}
+
+/// Log the invariant violations found by the engine in paranoid mode since last time
+fn log_violations(engine: &mut Engine) {
+    for violation in engine.take_violations() {
+        log::error!(
+            tx = violation.tx,
+            client = violation.client,
+            error:% = violation.error;
+            "Invariant violated"
+        );
+    }
+}
+
+/// Print a client's statement to stdout
+fn write_statement(history: &History, options: &StatementOptions) -> Result<()> {
+    let entries = history.statement(options.client, options.from..=options.to);
+    match options.format {
+        Format::Csv => {
+            let mut wtr = csv::CsvWriterBuilder::new(std::io::stdout()).build();
+            for entry in entries {
+                wtr.serialize(entry)?;
+            }
+            wtr.flush()?;
+        }
+        Format::Json => {
+            let entries: Vec<_> = entries.collect();
+            serde_json::to_writer_pretty(std::io::stdout(), &entries)?;
+            println!();
+        }
+    }
+    Ok(())
+}
+
+/// Print the accounts as they were at each of the points given in `options`.
+///
+/// The whole input is loaded in memory, as it must be replayed once per point.
+fn run_replay(rows: Vec<Row>, engine: Engine, options: &ReplayOptions) -> Result<()> {
+    let mut replay = Replay::new(rows, engine, options.checkpoint_every);
+
+    let mut wtr = csv::CsvWriterBuilder::new(std::io::stdout()).build();
+    for point in &options.points {
+        let engine = match replay.at(*point) {
+            Ok(engine) => engine,
+            Err(err) => {
+                log::error!(point:% = point, error:% = err; "Error replaying");
+                continue;
+            }
+        };
+        let mut accounts: Vec<_> = engine.get_accounts().values().collect();
+        accounts.sort_by_key(|account| account.id);
+        for account in accounts {
+            wtr.serialize(ReplayOutRecord::new(point.to_string(), account))?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Run the input through the engine, and print a report of what would happen instead of the accounts
+fn run_dry(rows: impl Iterator<Item = Row>, mut engine: Engine) -> Result<()> {
+    let mut report = DryRunReport::new();
+    let rows = rows.enumerate().map(|(index, row)| (index + 1, row));
+    let batches = Batches::new(rows, |(_, row): &(usize, Row)| csv::batch_of(row))
+        .with_unknown(|(_, row)| csv::is_unknown_batch(row));
+    for rows in batches {
+        // A row that cannot be read rolls its whole batch back
+        let invalid = rows.iter().any(|(_, row)| row.is_err());
+        if invalid {
+            engine.skip(rows.len() as u64);
+        }
+        let (mut lines, mut records) = (vec![], vec![]);
+        for (line, row) in rows {
+            match row {
+                Ok(record) if invalid => report.rolled_back(line, &record),
+                Ok(record) => {
+                    lines.push(line);
+                    records.push(record);
+                }
+                Err(_) => report.invalid(line),
+            }
+        }
+        match records.as_slice() {
+            [] => {}
+            [record] if record.batch.is_none() => {
+                let result = engine.process(record);
+                report.record(lines[0], record, &result);
+            }
+            _ => {
+                let result = engine.process_batch(&records);
+                report.record_batch(&lines, &records, &result);
+            }
+        }
+        log_violations(&mut engine);
+    }
+    let report = report.with_accounts(&engine);
+    serde_json::to_writer_pretty(std::io::stdout(), &report)?;
+    println!();
+    Ok(())
+}
 
 fn main() -> Result<()> {
     // very basic option parsing
-    let file_path = std::env::args().nth(1).unwrap_or_else(|| {
-        eprintln!("Missing filename argument");
+    let options = options::Options::from_env().unwrap_or_else(|err| {
+        eprintln!("{}", err);
         std::process::exit(1)
     });
+    logging::init(options.log_format, options.log_filter)?;
 
-    let mut rdr = csv::csv_reader_from_file((file_path).as_ref())?;
+    let mut rdr = csv::csv_reader_from_file(&options.file_path)?;
+    let limits = match &options.limits_path {
+        Some(limits_path) => Limits::from_reader(csv::csv_reader_from_file(limits_path)?)?,
+        None => Limits::default(),
+    };
+    // Options require a house account with fees
+    let fees = match (&options.fees_path, options.house_account) {
+        (Some(fees_path), Some(house)) => {
+            FeeSchedule::from_reader(csv::csv_reader_from_file(fees_path)?, house)?
+        }
+        _ => FeeSchedule::default(),
+    };
+    let risk = match &options.risk_rules_path {
+        Some(risk_rules_path) => Risk::from_reader(csv::csv_reader_from_file(risk_rules_path)?)?,
+        None => Risk::default(),
+    };
 
     // Start Engine thread with appropriate communication channel
     // How communication is handled, how results are printed etc. are left to the closure to implement them.
-    let (tx, rx) = std::sync::mpsc::sync_channel::<Record>(1); // I don't need to feed the engine faster than this
-    let mut engine = engine::Engine::new();
+    // Records are handed over in chunks, so that the reader and the engine don't contend on every record
+    let (mut tx, mut rx) =
+        channel::chunked::<(usize, Row)>(options.channel_capacity, options.chunk_size);
+    let flag_negative = options.policy.allow_negative;
+    let show_overdraft = limits.has_overdrafts();
+    let show_interest = options.interest.is_some();
+    let show_lock_reason = options.auto_lock.is_enabled();
+    let accrue_days = options.accrue_days;
+    let paranoid = options.paranoid;
+    let reconcile_path = options.reconcile_path;
+    let journal_path = options.journal_path;
+    let alerts_path = options.alerts_path;
+    let rejects_path = options.rejects_path;
+    let command = options.command;
+    if command == Command::DryRun {
+        let engine = Engine::with_policy(options.policy)
+            .with_auto_lock(options.auto_lock)
+            .with_unlock(options.allow_unlock)
+            .with_paranoid(paranoid)
+            .with_auth_expiry(options.auth_expiry)
+            .with_clock_skew(options.clock_skew)
+            .with_now(Some(Timestamp::now()))
+            .with_limits(limits)
+            .with_fees(fees)
+            .with_interest(options.interest)
+            .with_risk(risk);
+        return run_dry(csv::read_rows(&mut rdr), engine);
+    }
+    let mut engine = Engine::with_policy(options.policy)
+        .with_auto_lock(options.auto_lock)
+        .with_unlock(options.allow_unlock)
+        .with_paranoid(paranoid)
+        .with_auth_expiry(options.auth_expiry)
+        .with_clock_skew(options.clock_skew)
+        .with_now(Some(Timestamp::now()))
+        .with_limits(limits)
+        .with_fees(fees)
+        .with_interest(options.interest)
+        .with_risk(risk)
+        .with_journal(journal_path.is_some())
+        .with_history(matches!(command, Command::Statement(_)));
+    if let Command::Replay(replay_options) = &command {
+        let rows = csv::read_rows(&mut rdr)
+            .enumerate()
+            .inspect(|(index, row)| {
+                if let Err(invalid) = row {
+                    log::error!(line = index + 1, error:% = invalid.error; "Error reading record");
+                }
+            })
+            .map(|(_, row)| row)
+            .collect();
+        return run_replay(rows, engine, replay_options);
+    }
+
+    // Tell the customer-service system about locks and disputes, delivering what a previous run left
+    let mut delivery = None;
+    if !options.webhooks.is_empty() {
+        let mut notifier = Notifier::new(options.webhooks)
+            .with_retries(options.webhook_retries, Duration::from_millis(100));
+        if let Some(secret_path) = &options.webhook_secret_path {
+            let secret = std::fs::read_to_string(secret_path)?;
+            notifier = notifier.with_secret(secret.trim_end().as_bytes().to_vec());
+        }
+        let outbox = Outbox::open(&options.outbox_path, &notifier.urls())?;
+        let (observer, handle) = webhook::start(notifier, outbox);
+        engine = engine.with_observer(observer);
+        delivery = Some(handle);
+    }
+
+    // Expose what the engine is doing while it runs
+    let metrics = match &options.metrics_address {
+        Some(address) => {
+            let metrics = Arc::new(Metrics::new());
+            metrics::serve(TcpListener::bind(address)?, metrics.clone());
+            Some(metrics)
+        }
+        None => None,
+    };
+    let engine_metrics = metrics.clone();
+    // Rejected records are written as they come, so the file is opened right away
+    let mut rejects = rejects_path
+        .as_deref()
+        .map(csv::csv_writer_to_file)
+        .transpose()?;
     let handle = std::thread::spawn(move || {
-        eprintln!("Starting Engine");
+        log::info!("Starting engine");
 
-        while let Ok(record) = rx.recv() {
-            if let Err(err) = engine.process(&record) {
-                eprintln!("Error processing record {:?}: {}", record, err);
+        // Records of the same batch are applied all together
+        let received = rx.iter().inspect(|_| {
+            if let Some(metrics) = &engine_metrics {
+                metrics.dequeued();
+            }
+        });
+        let batches = Batches::new(received, |(_, row): &(usize, Row)| csv::batch_of(row))
+            .with_unknown(|(_, row)| csv::is_unknown_batch(row));
+        for rows in batches {
+            let (first_line, size) = (rows[0].0, rows.len());
+            let batch = rows.iter().find_map(|(_, row)| csv::batch_of(row));
+            // The reader logged the rows it could not read, which roll their whole batch back
+            let invalid = rows.iter().any(|(_, row)| row.is_err());
+            let (lines, records): (Vec<_>, Vec<_>) = rows
+                .into_iter()
+                .filter_map(|(line, row)| Some((line, row.ok()?)))
+                .unzip();
+            if invalid {
+                engine.skip(size as u64);
+                if let Some(batch) = batch {
+                    if let Some(metrics) = &engine_metrics {
+                        metrics.rolled_back(&records);
+                    }
+                    for (line, record) in lines.iter().zip(&records) {
+                        write_rejection(&mut rejects, *line, record, "Batch rolled back".into());
+                    }
+                    log::warn!(batch = batch, line = first_line, size = size; "Batch rolled back");
+                }
+                continue;
+            }
+            let start = Instant::now();
+            match records.as_slice() {
+                [record] if record.batch.is_none() => {
+                    let result = engine.process(record);
+                    if let Some(metrics) = &engine_metrics {
+                        metrics.record(record, &result, start.elapsed());
+                    }
+                    if let Err(err) = &result {
+                        log_rejection(lines[0], record, err);
+                        write_rejection(&mut rejects, lines[0], record, format!("{:#}", err));
+                    }
+                }
+                _ => {
+                    let result = engine.process_batch(&records);
+                    if let Some(metrics) = &engine_metrics {
+                        metrics.record_batch(&records, &result, start.elapsed());
+                    }
+                    if let Err(err) = &result {
+                        log_rejection(lines[err.index], &records[err.index], &err.source);
+                        for (index, (line, record)) in lines.iter().zip(&records).enumerate() {
+                            let error = if index == err.index {
+                                format!("{:#}", err.source)
+                            } else {
+                                "Batch rolled back".into()
+                            };
+                            write_rejection(&mut rejects, *line, record, error);
+                        }
+                        log::warn!(
+                            batch = records[0].batch.unwrap_or_default(),
+                            line = lines[0],
+                            size = records.len();
+                            "Batch rolled back"
+                        );
+                    }
+                }
+            }
+            log_violations(&mut engine);
+            if let Some(metrics) = &engine_metrics {
+                metrics.refresh(&engine, false);
             }
         }
+        log_wait_stats("engine", &rx.stats());
+        if let Some(Err(err)) = rejects.as_mut().map(|wtr| wtr.flush()) {
+            log::error!(error:% = err; "Error writing rejects");
+        }
 
-        eprintln!("Stopping Engine and printing results");
-        // retrieve accounts data
-        let accounts = engine.get_accounts();
+        // Interest is accrued on the final balances
+        if let Some(days) = accrue_days {
+            if let Err(err) = engine.accrue(0, days) {
+                log::error!(error:% = err; "Error accruing interest");
+            }
+        }
+        if let Some(metrics) = &engine_metrics {
+            metrics.refresh(&engine, true);
+        }
 
-        // build CSV writer
-        let mut wtr = csv::CsvWriterBuilder::new(std::io::stdout()).build();
+        if paranoid {
+            for violation in engine.audit() {
+                log::error!(error:% = format!("{:#}", violation); "Invariant violated");
+            }
+        }
 
-        // Start writing
-        for account in accounts.values() {
-            let out_record = OutRecord::from(account);
-            if let Err(err) = wtr.serialize(out_record) {
-                eprintln!("Error writing record: {}", err);
+        log::info!("Stopping engine and printing results");
+        match (&command, engine.get_history()) {
+            (Command::Statement(statement), Some(history)) => {
+                if let Err(err) = write_statement(history, statement) {
+                    log::error!(error:% = err; "Error writing statement");
+                }
             }
+            _ => write_accounts(
+                &engine,
+                flag_negative,
+                show_overdraft,
+                show_interest,
+                show_lock_reason,
+            ),
         }
-        if let Err(err) = wtr.flush() {
-            eprintln!("Error flushing writer: {}", err);
+
+        // Export the journal for the accounting system
+        if let (Some(journal_path), Some(journal)) = (journal_path, engine.get_journal()) {
+            if let Err(err) = journal.verify() {
+                log::error!(error:% = err; "Error verifying journal");
+            }
+            if let Err(err) = write_journal(&journal_path, journal) {
+                log::error!(error:% = err; "Error writing journal");
+            }
         }
-        eprintln!("Done");
-    });
 
-    // Read from CSV and send to Engine
-    for record in rdr.deserialize() {
-        match record {
-            Ok(record) => {
-                if let Err(err) = tx.send(record) {
-                    eprintln!("Error sending record: {}", err);
-                }
+        // Hand the records caught by risk rules over for review
+        if let Some(alerts_path) = alerts_path {
+            if let Err(err) = write_alerts(&alerts_path, engine.get_alerts()) {
+                log::error!(error:% = err; "Error writing alerts");
             }
-            Err(err) => eprintln!("Error reading record: {}", err),
+        }
+
+        // Prove no money was created or destroyed
+        if let Some(reconcile_path) = reconcile_path {
+            let reconciliation = Reconciliation::new(&engine);
+            if let Err(err) = write_reconciliation(&reconcile_path, &reconciliation) {
+                log::error!(error:% = err; "Error writing reconciliation report");
+            }
+            if !reconciliation.is_balanced() {
+                return Err(anyhow!("Reconciliation failed"));
+            }
+        }
+        log::info!("Done");
+        Ok(())
+    });
+
+    // Read from CSV and send to Engine, with line numbers counted from 1, header excluded
+    for (index, row) in csv::read_rows(&mut rdr).enumerate() {
+        let line = index + 1;
+        if let Err(invalid) = &row {
+            log::error!(line = line, error:% = invalid.error; "Error reading record");
+        }
+        if let Some(metrics) = &metrics {
+            metrics.queued();
+        }
+        if let Err(err) = tx.send((line, row)) {
+            log::error!(line = line, error:% = err; "Error sending record");
+            break;
         }
     }
 
-    // Drop the sender so the receiver will stop
-    drop(tx);
-    handle.join().expect("Engine thread panicked");
+    // Send the last chunk and close the channel so the receiver will stop
+    match tx.finish() {
+        Ok(stats) => log_wait_stats("reader", &stats),
+        Err(err) => log::error!(error:% = err; "Error sending record"),
+    }
+    let result = handle.join().expect("Engine thread panicked");
+
+    // The engine is gone with its observers, so the delivery ends once the outbox is delivered
+    if let Some(delivery) = delivery {
+        delivery.join().expect("Webhook delivery thread panicked");
+    }
+    result?;
 
-    eprintln!("Main thread done");
+    log::debug!("Main thread done");
     Ok(())
 }
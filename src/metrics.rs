@@ -0,0 +1,541 @@
+use crate::deser::Record;
+use crate::engine::{BatchError, Engine};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1,
+];
+
+/// Record types counted under their own `type` label, any other one being counted as `other`
+const TYPES: [&str; 11] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "reversal",
+    "authorize",
+    "capture",
+    "release",
+    "unlock",
+    "accrue",
+];
+
+/// Rejection reasons counted under their own `reason` label, any other one being counted as
+/// `other`, so that a formatted error cannot create a label value per record
+const REASONS: [&str; 41] = [
+    "Account is locked",
+    "Account is the house account",
+    "Account locked by a chargeback",
+    "Account not found",
+    "Account not locked",
+    "Amount exceeds authorized amount",
+    "Amount exceeds disputed amount",
+    "Amount exceeds undisputed amount",
+    "Amount must be non-negative",
+    "Amount must be positive",
+    "Authorization does not belong to client",
+    "Authorization not found",
+    "Balance limit exceeded",
+    "Batch rolled back",
+    "Days must be positive",
+    "Deposit withdrawn right away",
+    "Dispute rate exceeded",
+    "Duplicate authorization",
+    "House account not configured",
+    "Insufficient funds",
+    "Insufficient held funds",
+    "Interest not configured",
+    "Missing amount",
+    "Only deposits can be disputed",
+    "Overflow",
+    "Timestamp before referenced transaction",
+    "Timestamp in the future",
+    "Timestamp out of order",
+    "Too many withdrawals",
+    "Transaction already reversed",
+    "Transaction already under dispute",
+    "Transaction charged back",
+    "Transaction does not belong to client",
+    "Transaction not found",
+    "Transaction not under dispute",
+    "Transaction reversed",
+    "Transaction under dispute",
+    "Unknown command",
+    "Unlock not allowed",
+    "Window withdrawal limit exceeded",
+    "Withdrawal limit exceeded",
+];
+
+/// How long the gauges read from the engine may be out of date
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latency histogram, with cumulative buckets as Prometheus expects them
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS) {
+            if secs <= bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += elapsed;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, bound) in self.counts.iter().zip(BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum.as_secs_f64());
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// Metrics read from the engine
+#[derive(Debug, Default)]
+struct Gauges {
+    accounts: usize,
+    locked: usize,
+    open_disputes: usize,
+    held: Decimal,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    processed: BTreeMap<&'static str, u64>,
+    rejected: BTreeMap<(&'static str, &'static str), u64>, // Per (type, reason)
+    record_latency: Histogram,
+    batch_latency: Histogram,
+    gauges: Gauges,
+    refreshed: Option<Instant>,
+}
+
+/// Metrics of a running engine, exposed in the Prometheus text format.
+///
+/// Counters are updated as records are processed, gauges are read from the engine at most once
+/// a second. Label values are bounded, see `TYPES` and `REASONS`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<State>,
+    queue_depth: AtomicI64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for the outcome of processing `record`, which took `elapsed`
+    pub fn record(&self, record: &Record, result: &anyhow::Result<()>, elapsed: Duration) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        state.record_latency.observe(elapsed);
+        state.count(record, result.as_ref().err().map(reason));
+    }
+
+    /// Account for the outcome of processing a batch of `records`, which took `elapsed`.
+    ///
+    /// If the batch was rolled back, all its records are rejected, not only the culprit.
+    pub fn record_batch(
+        &self,
+        records: &[Record],
+        result: &Result<(), BatchError>,
+        elapsed: Duration,
+    ) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        state.batch_latency.observe(elapsed);
+        for (index, record) in records.iter().enumerate() {
+            let reason = match result {
+                Ok(()) => None,
+                Err(err) if err.index == index => Some(reason(&err.source)),
+                Err(_) => Some("Batch rolled back"),
+            };
+            state.count(record, reason);
+        }
+    }
+
+    /// Account for a batch of `records` rolled back without being processed, because some of its
+    /// rows could not be read
+    pub fn rolled_back(&self, records: &[Record]) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        for record in records {
+            state.count(record, Some("Batch rolled back"));
+        }
+    }
+
+    /// Read the gauges from `engine`, unless they were read less than a second ago and `force` is
+    /// not set
+    pub fn refresh(&self, engine: &Engine, force: bool) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if !force
+            && state
+                .refreshed
+                .is_some_and(|refreshed| refreshed.elapsed() < REFRESH_INTERVAL)
+        {
+            return;
+        }
+        let accounts = engine.get_accounts();
+        state.gauges = Gauges {
+            accounts: accounts.len(),
+            locked: accounts.values().filter(|account| account.locked).count(),
+            open_disputes: engine.count_open_disputes(),
+            held: accounts.values().map(|account| account.held).sum(),
+        };
+        state.refreshed = Some(Instant::now());
+    }
+
+    /// A record was queued for the engine
+    pub fn queued(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The engine took a record off its queue
+    pub fn dequeued(&self) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// All the metrics, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let Ok(state) = self.state.lock() else {
+            return out;
+        };
+
+        header(
+            &mut out,
+            "records_processed_total",
+            "counter",
+            "Records processed, per type",
+        );
+        for (kind, count) in &state.processed {
+            let _ = writeln!(
+                out,
+                "transaction_engine_records_processed_total{{type=\"{}\"}} {}",
+                escape(kind),
+                count
+            );
+        }
+        header(
+            &mut out,
+            "records_rejected_total",
+            "counter",
+            "Records rejected, per type and reason",
+        );
+        for ((kind, reason), count) in &state.rejected {
+            let _ = writeln!(
+                out,
+                "transaction_engine_records_rejected_total{{type=\"{}\",reason=\"{}\"}} {}",
+                escape(kind),
+                escape(reason),
+                count
+            );
+        }
+
+        let gauges = &state.gauges;
+        for (name, help, value) in [
+            ("accounts", "Accounts", gauges.accounts.to_string()),
+            (
+                "accounts_locked",
+                "Locked accounts",
+                gauges.locked.to_string(),
+            ),
+            (
+                "open_disputes",
+                "Transactions under dispute",
+                gauges.open_disputes.to_string(),
+            ),
+            (
+                "held",
+                "Funds held, over all accounts",
+                gauges.held.to_string(),
+            ),
+            (
+                "queue_depth",
+                "Records waiting for the engine",
+                self.queue_depth.load(Ordering::Relaxed).to_string(),
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "transaction_engine_{} {}", name, value);
+        }
+
+        let name = "transaction_engine_processing_seconds";
+        header(
+            &mut out,
+            "processing_seconds",
+            "histogram",
+            "Time spent processing a single record or a whole batch",
+        );
+        state
+            .record_latency
+            .render(&mut out, name, "unit=\"record\",");
+        state
+            .batch_latency
+            .render(&mut out, name, "unit=\"batch\",");
+        out
+    }
+}
+
+impl State {
+    fn count(&mut self, record: &Record, reason: Option<&'static str>) {
+        let kind = label(&TYPES, &record.command);
+        *self.processed.entry(kind).or_default() += 1;
+        if let Some(reason) = reason {
+            *self.rejected.entry((kind, reason)).or_default() += 1;
+        }
+    }
+}
+
+/// `value` if it is one of `known`, `other` otherwise
+fn label(known: &[&'static str], value: &str) -> &'static str {
+    known
+        .iter()
+        .find(|known| **known == value)
+        .copied()
+        .unwrap_or("other")
+}
+
+/// Label of the reason why a record was rejected with `err`, i.e. its root cause
+fn reason(err: &anyhow::Error) -> &'static str {
+    label(&REASONS, &err.root_cause().to_string())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP transaction_engine_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE transaction_engine_{} {}", name, kind);
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer `GET /metrics` on `listener` with the metrics, from a thread of its own
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = respond(stream, &metrics) {
+                log::warn!(error:% = err; "Error serving metrics");
+            }
+        }
+    })
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", metrics.render())
+        }
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn record(command: &str, client: u64, tx: u64, amount: Option<Decimal>) -> Record {
+        Record {
+            command: command.to_string(),
+            client,
+            tx,
+            amount,
+            batch: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        let mut engine = Engine::new();
+        for record in [
+            record("deposit", 1, 1, Some(Decimal::TEN)),
+            record("dispute", 1, 1, None),
+            record("withdrawal", 1, 2, Some(Decimal::ONE)),
+            record("withdrawal", 2, 3, Some(Decimal::ONE)),
+            record("deposit\n1", 2, 6, Some(Decimal::ONE)),
+        ] {
+            let result = engine.process(&record);
+            metrics.record(&record, &result, Duration::from_micros(20));
+        }
+        let batch = [
+            record("deposit", 3, 4, Some(Decimal::ONE)),
+            record("withdrawal", 3, 5, Some(Decimal::TWO)),
+        ];
+        let result = engine.process_batch(&batch);
+        metrics.record_batch(&batch, &result, Duration::from_millis(2));
+        metrics.refresh(&engine, true);
+        metrics.queued();
+        metrics.queued();
+        metrics.dequeued();
+
+        let text = metrics.render();
+        for line in [
+            "# TYPE transaction_engine_records_processed_total counter",
+            "transaction_engine_records_processed_total{type=\"deposit\"} 2",
+            "transaction_engine_records_processed_total{type=\"withdrawal\"} 3",
+            "transaction_engine_records_rejected_total{type=\"withdrawal\",reason=\"Insufficient funds\"} 3",
+            "transaction_engine_records_rejected_total{type=\"deposit\",reason=\"Batch rolled back\"} 1",
+            "transaction_engine_records_rejected_total{type=\"other\",reason=\"Unknown command\"} 1",
+            "transaction_engine_accounts 2",
+            "transaction_engine_accounts_locked 0",
+            "transaction_engine_open_disputes 1",
+            "transaction_engine_held 10",
+            "transaction_engine_queue_depth 1",
+            "transaction_engine_processing_seconds_bucket{unit=\"record\",le=\"0.00001\"} 0",
+            "transaction_engine_processing_seconds_bucket{unit=\"record\",le=\"0.00005\"} 5",
+            "transaction_engine_processing_seconds_bucket{unit=\"batch\",le=\"+Inf\"} 1",
+            "transaction_engine_processing_seconds_count{unit=\"batch\"} 1",
+            "transaction_engine_processing_seconds_sum{unit=\"batch\"} 0.002",
+        ] {
+            assert!(text.lines().any(|rendered| rendered == line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(label(&TYPES, "capture"), "capture");
+        assert_eq!(label(&TYPES, "Capture"), "other");
+        assert_eq!(
+            reason(&anyhow::anyhow!("Insufficient funds")),
+            "Insufficient funds"
+        );
+        let err = anyhow::anyhow!("Insufficient funds").context("Rejected by risk rule velocity");
+        assert_eq!(reason(&err), "Insufficient funds");
+        assert_eq!(reason(&anyhow::anyhow!("Client {} not found", 1)), "other");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::new());
+        metrics.queued();
+        serve(listener, metrics);
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\ntransaction_engine_queue_depth 1\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_reasons() {
+        // Static messages of the crate that never reject a record: options, files, audits and I/O
+        let not_reasons = [
+            "--checkpoint-every must be positive",
+            "Account locked without a chargeback",
+            "Account locked without a reason",
+            "Available funds do not match the journal",
+            "Chunk size must be positive",
+            "Fees must be non-negative",
+            "Held funds do not match the journal",
+            "Interest rate must be positive",
+            "Invalid HTTP response",
+            "Journal is not balanced",
+            "Line out of range",
+            "Logger already installed",
+            "Minimum fee above maximum fee",
+            "Missing --at-tx or --at-line for replay",
+            "Missing --client for statement",
+            "Missing --fees for --house-account",
+            "Missing --house-account for --fees",
+            "Missing --interest-rate for --accrue",
+            "Missing filename argument",
+            "Negative balance beyond overdraft",
+            "Negative held funds",
+            "Only http:// webhooks are supported",
+            "Option --dry-run cannot be used with a command",
+            "Overdraft must be non-negative",
+            "Receiver gone",
+            "Reconciliation failed",
+            "Total does not match available plus held",
+        ];
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let source = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            // Errors, and the reasons of risk rules
+            for pattern in ["anyhow!(\"", "self.action, \""] {
+                for rest in source.split(pattern).skip(1) {
+                    let Some((message, rest)) = rest.split_once('"') else {
+                        continue;
+                    };
+                    if !rest.starts_with(')') || not_reasons.contains(&message) {
+                        continue;
+                    }
+                    assert!(REASONS.contains(&message), "{}", message);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::account::{Account, Operation};
+use crate::auto_lock::LockReason;
+use crate::deser::Record;
+use crate::ids::{ClientId, TxId};
+use rust_decimal::Decimal;
+use std::sync::Arc;
+
+/// State of a transaction's dispute
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisputeState {
+    /// Nothing is under dispute
+    Closed,
+    /// This amount is under dispute
+    Open(Decimal),
+}
+
+/// Hooks the engine calls as it processes records, e.g. to send notifications, collect metrics or
+/// feed an audit sink.
+///
+/// Every method does nothing by default. The records of a batch are only observed once the batch
+/// commits: a batch rolled back only shows as the rejection of the record that caused it.
+pub trait EngineObserver: Send + Sync {
+    /// An operation was applied to an account, moving it from `before` to `after`. An operation with
+    /// a fee is followed by the `Fee` and `FeeIncome` operations.
+    fn on_operation(
+        &self,
+        _tx: TxId,
+        _operation: Operation,
+        _amount: Decimal,
+        _before: &Account,
+        _after: &Account,
+    ) {
+    }
+
+    /// A record was rejected
+    fn on_rejection(&self, _record: &Record, _err: &anyhow::Error) {}
+
+    /// An account was opened
+    fn on_account_created(&self, _account: &Account) {}
+
+    /// An account got locked, by a chargeback or by auto-lock
+    fn on_lock(&self, _before: &Account, _after: &Account, _reason: LockReason) {}
+
+    /// An account got unlocked by an admin
+    fn on_unlock(&self, _before: &Account, _after: &Account) {}
+
+    /// The dispute of transaction `tx` went from `before` to `after`
+    fn on_dispute_change(
+        &self,
+        _client_id: ClientId,
+        _tx: TxId,
+        _before: DisputeState,
+        _after: DisputeState,
+    ) {
+    }
+}
+
+/// An event held back until the batch it belongs to commits
+#[derive(Clone, Debug)]
+enum Held {
+    Operation(TxId, Operation, Decimal, Account, Account),
+    AccountCreated(Account),
+    Lock(Account, Account, LockReason),
+    Unlock(Account, Account),
+    DisputeChange(ClientId, TxId, DisputeState, DisputeState),
+}
+
+/// The observers of an engine, calling every one of them in turn.
+///
+/// Between `hold` and `release`, events are held back instead, except rejections. Being part of
+/// the engine, held events are dropped with it when a batch is rolled back to a snapshot.
+#[derive(Clone, Default)]
+pub struct Observers {
+    observers: Vec<Arc<dyn EngineObserver>>,
+    held: Option<Vec<Held>>,
+}
+
+impl Observers {
+    /// Call `observer` too, after the ones added before
+    pub fn push(&mut self, observer: Arc<dyn EngineObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Hold events back until `release`
+    pub fn hold(&mut self) {
+        self.held = Some(Vec::new());
+    }
+
+    /// Hand the events held back over to the observers, and stop holding them back
+    pub fn release(&mut self) {
+        for event in self.held.take().into_iter().flatten() {
+            self.notify(&event);
+        }
+    }
+
+    fn dispatch(&mut self, event: Held) {
+        match &mut self.held {
+            Some(held) => held.push(event),
+            None => self.notify(&event),
+        }
+    }
+
+    fn notify(&self, event: &Held) {
+        for observer in &self.observers {
+            match event {
+                Held::Operation(tx, operation, amount, before, after) => {
+                    observer.on_operation(*tx, *operation, *amount, before, after)
+                }
+                Held::AccountCreated(account) => observer.on_account_created(account),
+                Held::Lock(before, after, reason) => observer.on_lock(before, after, *reason),
+                Held::Unlock(before, after) => observer.on_unlock(before, after),
+                Held::DisputeChange(client_id, tx, before, after) => {
+                    observer.on_dispute_change(*client_id, *tx, *before, *after)
+                }
+            }
+        }
+    }
+
+    /// See `EngineObserver::on_operation`
+    pub fn on_operation(
+        &mut self,
+        tx: TxId,
+        operation: Operation,
+        amount: Decimal,
+        before: &Account,
+        after: &Account,
+    ) {
+        if !self.observers.is_empty() {
+            self.dispatch(Held::Operation(
+                tx,
+                operation,
+                amount,
+                before.clone(),
+                after.clone(),
+            ));
+        }
+    }
+
+    /// See `EngineObserver::on_rejection`. Rejections are never held back.
+    pub fn on_rejection(&self, record: &Record, err: &anyhow::Error) {
+        for observer in &self.observers {
+            observer.on_rejection(record, err);
+        }
+    }
+
+    /// See `EngineObserver::on_account_created`
+    pub fn on_account_created(&mut self, account: &Account) {
+        if !self.observers.is_empty() {
+            self.dispatch(Held::AccountCreated(account.clone()));
+        }
+    }
+
+    /// See `EngineObserver::on_lock`
+    pub fn on_lock(&mut self, before: &Account, after: &Account, reason: LockReason) {
+        if !self.observers.is_empty() {
+            self.dispatch(Held::Lock(before.clone(), after.clone(), reason));
+        }
+    }
+
+    /// See `EngineObserver::on_unlock`
+    pub fn on_unlock(&mut self, before: &Account, after: &Account) {
+        if !self.observers.is_empty() {
+            self.dispatch(Held::Unlock(before.clone(), after.clone()));
+        }
+    }
+
+    /// See `EngineObserver::on_dispute_change`
+    pub fn on_dispute_change(
+        &mut self,
+        client_id: ClientId,
+        tx: TxId,
+        before: DisputeState,
+        after: DisputeState,
+    ) {
+        if !self.observers.is_empty() {
+            self.dispatch(Held::DisputeChange(client_id, tx, before, after));
+        }
+    }
+}
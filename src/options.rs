@@ -0,0 +1,739 @@
+use crate::account::{AccountPolicy, LockPolicy, Operation};
+use crate::auto_lock::AutoLockPolicy;
+use crate::ids::{ClientId, TxId};
+use crate::interest::{DayCount, InterestPolicy};
+use crate::logging::{LogFilter, LogFormat};
+use crate::replay::ReplayPoint;
+use crate::webhook::Endpoint;
+use crate::window::Span;
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use std::path::PathBuf;
+
+/// Output format of a statement
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+/// Options of the `statement` command, printing a client's running balance instead of the accounts
+#[derive(Debug, Eq, PartialEq)]
+pub struct StatementOptions {
+    pub client: ClientId,
+    pub from: TxId,
+    pub to: TxId,
+    pub format: Format,
+}
+
+/// Options of the `replay` command, printing the accounts as they were at given points of the input
+#[derive(Debug, Eq, PartialEq)]
+pub struct ReplayOptions {
+    pub points: Vec<ReplayPoint>,
+    pub checkpoint_every: usize,
+}
+
+/// What to do with the input
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Process the input and print the accounts
+    Run,
+    Statement(StatementOptions),
+    Replay(ReplayOptions),
+    /// Process the input and print a report of what would be rejected and the final balances
+    DryRun,
+}
+
+/// Command line options.
+///
+/// Usage: `transaction-engine [statement|replay] [OPTIONS] input_file`
+///
+/// With the `statement` command, the whole input is processed, then a client's running balance is
+/// printed instead of the accounts:
+/// - `--client <id>`: the client (required).
+/// - `--from <tx>`, `--to <tx>`: range of tx ids to print, inclusive. Default is all of them.
+/// - `--format <csv|json>`: default is `csv`.
+///
+/// With the `replay` command, the accounts are printed as they were at each of the given points:
+/// - `--at-tx <tx>`: right after the first record with this tx id. Can be repeated.
+/// - `--at-line <n>`: right after the first `n` records. Can be repeated.
+/// - `--checkpoint-every <n>`: records between checkpoints the replay restarts from. Default is 10000.
+///
+/// Options:
+/// - `--dry-run`: validate the input, printing a JSON report of what would be rejected, per type and
+///   per reason, and what the final balances would be, instead of the accounts. No other file is written.
+/// - `--locked-allow <ops>`: comma separated list of operations (`deposit`, `withdrawal`, `dispute`,
+///   `resolve`, `chargeback`, `reversal`, `authorize`, `capture`, `release`) still allowed on locked
+///   accounts, or `none`. Default is `resolve,chargeback,capture,release`.
+/// - `--allow-negative`: disputes may drive available funds negative, and chargebacks may leave a
+///   negative total (a debt). Adds `negative` and `debt` columns to the output.
+/// - `--max-open-disputes <n>`, `--max-disputed <amount>`: lock accounts with more than `n`
+///   transactions or more than `amount` under dispute. Adds a `lock_reason` column to the output.
+/// - `--allow-unlock`: apply `unlock` records, which unlock accounts that never had a chargeback.
+///   Otherwise they are rejected.
+/// - `--paranoid`: audit the client's account after every operation, and all of them at the end.
+/// - `--reconcile <file>`: write a reconciliation report to `file` at the end of the run, and fail
+///   the run if money was created or destroyed.
+/// - `--journal <file>`: keep a double-entry journal of every operation and export it to `file` at
+///   the end of the run. With `--paranoid`, balances are also verified against the journal.
+/// - `--limits <file>`: CSV of per-account limits (`client`, `max_withdrawal`, `max_window_withdrawal`,
+///   `window`, `max_balance`, `overdraft`), a row without client holding the default ones. Empty means
+///   no limit. With overdrafts, adds `overdraft` and `overdrawn` columns to the output.
+/// - `--rejects <file>`: write every rejected record to `file` as the run goes (`line`, `type`,
+///   `client`, `tx`, `amount`, `error`), those of rolled back batches included.
+/// - `--fees <file>`: CSV of the fees charged per transaction type (`type`, `flat`, `percent`, `min`,
+///   `max`), for `withdrawal` and `chargeback`. Fees are credited to the house account.
+/// - `--risk-rules <file>`: CSV of fraud/risk rules run before applying each record (`rule`, `action`,
+///   `count`, `window`, `percent`), see `Risk::from_reader`. Each rule flags or rejects what it catches.
+/// - `--alerts <file>`: write the records caught by risk rules to `file` at the end of the run.
+/// - `--webhook <url>`: POST a JSON event to `url` when an account gets locked or a dispute opens.
+///   Only `http://` URLs are supported. Can be repeated.
+/// - `--webhook-retries <n>`: retries of a failed webhook delivery, backing off from 100ms, before
+///   leaving the event in the outbox for the next run. Default is 3.
+/// - `--webhook-secret-file <file>`: sign the webhook payloads with the secret held in `file`.
+/// - `--outbox <file>`: where webhook events wait for their delivery, across runs. Default is
+///   `outbox.jsonl`.
+/// - `--metrics <addr>`: serve metrics in the Prometheus text format on `http://<addr>/metrics`, e.g.
+///   `127.0.0.1:9898`, while the engine runs.
+/// - `--channel-capacity <n>`: chunks of records read ahead of the engine. Default is 4.
+/// - `--chunk-size <n>`: records per chunk handed over to the engine. Default is 256.
+/// - `--log-level <filter>`: level of the events logged to `stderr`, by default and per module, e.g.
+///   `warn,webhook=debug`. Default is `info`.
+/// - `--log-format <text|json>`: default is `text`.
+/// - `--house-account <id>`: client id of the house account, earning the fees. Required with `--fees`,
+///   and only allowed with it. Records targeting it are rejected.
+/// - `--interest-rate <percent>`: yearly interest rate credited on available funds by `accrue` records,
+///   must be positive.
+/// - `--day-count <act/360|act/365>`: day-count convention of the interest. Default is `act/365`.
+/// - `--accrue <days>`: accrue `days` of interest at the end of the input, before printing anything.
+/// - `--auth-expiry <window>`: authorizations still open after `window` are released, either a number
+///   of the client's records or a duration (e.g. `7d`), see `Span`. Default is to never expire them.
+/// - `--clock-skew <secs>`: how much earlier than the latest one, or later than the current time, a
+///   record's timestamp may be. Default is 0.
+#[derive(Debug)]
+pub struct Options {
+    pub file_path: PathBuf,
+    pub policy: AccountPolicy,
+    pub auto_lock: AutoLockPolicy,
+    pub allow_unlock: bool,
+    pub paranoid: bool,
+    pub reconcile_path: Option<PathBuf>,
+    pub journal_path: Option<PathBuf>,
+    pub auth_expiry: Option<Span>,
+    pub clock_skew: u64,
+    pub limits_path: Option<PathBuf>,
+    pub rejects_path: Option<PathBuf>,
+    pub fees_path: Option<PathBuf>,
+    pub risk_rules_path: Option<PathBuf>,
+    pub alerts_path: Option<PathBuf>,
+    pub webhooks: Vec<Endpoint>,
+    pub webhook_retries: u32,
+    pub webhook_secret_path: Option<PathBuf>,
+    pub outbox_path: PathBuf,
+    pub metrics_address: Option<String>,
+    pub channel_capacity: usize,
+    pub chunk_size: usize,
+    pub log_filter: LogFilter,
+    pub log_format: LogFormat,
+    pub house_account: Option<ClientId>,
+    pub interest: Option<InterestPolicy>,
+    pub accrue_days: Option<Decimal>,
+    pub command: Command,
+}
+
+impl Options {
+    /// Parse options from the process arguments
+    pub fn from_env() -> Result<Self> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    /// Parse options from `args` (program name excluded)
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut file_path = None;
+        let mut policy = AccountPolicy::default();
+        let mut auto_lock = AutoLockPolicy::default();
+        let mut allow_unlock = false;
+        let mut paranoid = false;
+        let mut reconcile_path = None;
+        let mut journal_path = None;
+        let mut auth_expiry = None;
+        let mut clock_skew = 0;
+        let mut limits_path = None;
+        let mut rejects_path = None;
+        let mut fees_path = None;
+        let (mut risk_rules_path, mut alerts_path) = (None, None);
+        let mut webhooks = Vec::new();
+        let mut webhook_retries = 3;
+        let mut webhook_secret_path = None;
+        let mut outbox_path = PathBuf::from("outbox.jsonl");
+        let mut metrics_address = None;
+        let mut channel_capacity = 4;
+        let mut chunk_size = 256;
+        let mut log_filter = LogFilter::default();
+        let mut log_format = LogFormat::Text;
+        let mut house_account = None;
+        let (mut rate, mut day_count, mut accrue_days) = (None, DayCount::default(), None);
+        let mut dry_run = false;
+
+        let mut args = args.into_iter().peekable();
+        let command = args.next_if(|arg| arg == "statement" || arg == "replay");
+        let (mut client, mut from, mut to, mut format) = (None, TxId::MIN, TxId::MAX, Format::Csv);
+        let (mut points, mut checkpoint_every) = (vec![], 10_000);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--client" | "--from" | "--to" | "--format"
+                    if command.as_deref() != Some("statement") =>
+                {
+                    return Err(anyhow!("Option {} is for the statement command only", arg))
+                }
+                "--at-tx" | "--at-line" | "--checkpoint-every"
+                    if command.as_deref() != Some("replay") =>
+                {
+                    return Err(anyhow!("Option {} is for the replay command only", arg))
+                }
+                "--client" => client = Some(parse_number(&next_value(&mut args, &arg)?)?),
+                "--from" => from = parse_number(&next_value(&mut args, &arg)?)?,
+                "--to" => to = parse_number(&next_value(&mut args, &arg)?)?,
+                "--format" => format = parse_format(&next_value(&mut args, &arg)?)?,
+                "--at-tx" => {
+                    let tx = parse_number(&next_value(&mut args, &arg)?)?;
+                    points.push(ReplayPoint::Tx(tx));
+                }
+                "--at-line" => {
+                    let line = parse_number(&next_value(&mut args, &arg)?)?;
+                    points.push(ReplayPoint::Line(line));
+                }
+                "--checkpoint-every" => {
+                    checkpoint_every = parse_number(&next_value(&mut args, &arg)?)?;
+                    if checkpoint_every == 0 {
+                        return Err(anyhow!("--checkpoint-every must be positive"));
+                    }
+                }
+                "--locked-allow" => {
+                    let value = next_value(&mut args, &arg)?;
+                    policy.locked = parse_lock_policy(&value)?;
+                }
+                "--dry-run" if command.is_some() => {
+                    return Err(anyhow!("Option --dry-run cannot be used with a command"))
+                }
+                "--dry-run" => dry_run = true,
+                "--allow-negative" => policy.allow_negative = true,
+                "--paranoid" => paranoid = true,
+                "--max-open-disputes" => {
+                    auto_lock.max_open_disputes = Some(parse_number(&next_value(&mut args, &arg)?)?)
+                }
+                "--max-disputed" => {
+                    auto_lock.max_disputed = Some(parse_number(&next_value(&mut args, &arg)?)?)
+                }
+                "--allow-unlock" => allow_unlock = true,
+                "--reconcile" => {
+                    let value = next_value(&mut args, &arg)?;
+                    reconcile_path = Some(PathBuf::from(value));
+                }
+                "--journal" => {
+                    let value = next_value(&mut args, &arg)?;
+                    journal_path = Some(PathBuf::from(value));
+                }
+                "--limits" => {
+                    let value = next_value(&mut args, &arg)?;
+                    limits_path = Some(PathBuf::from(value));
+                }
+                "--fees" => {
+                    let value = next_value(&mut args, &arg)?;
+                    fees_path = Some(PathBuf::from(value));
+                }
+                "--rejects" => {
+                    let value = next_value(&mut args, &arg)?;
+                    rejects_path = Some(PathBuf::from(value));
+                }
+                "--risk-rules" => {
+                    let value = next_value(&mut args, &arg)?;
+                    risk_rules_path = Some(PathBuf::from(value));
+                }
+                "--alerts" => {
+                    let value = next_value(&mut args, &arg)?;
+                    alerts_path = Some(PathBuf::from(value));
+                }
+                "--webhook" => webhooks.push(next_value(&mut args, &arg)?.parse()?),
+                "--webhook-retries" => {
+                    webhook_retries = parse_number(&next_value(&mut args, &arg)?)?
+                }
+                "--webhook-secret-file" => {
+                    let value = next_value(&mut args, &arg)?;
+                    webhook_secret_path = Some(PathBuf::from(value));
+                }
+                "--outbox" => outbox_path = PathBuf::from(next_value(&mut args, &arg)?),
+                "--metrics" => metrics_address = Some(next_value(&mut args, &arg)?),
+                "--channel-capacity" => {
+                    channel_capacity = parse_number(&next_value(&mut args, &arg)?)?
+                }
+                "--chunk-size" => chunk_size = parse_number(&next_value(&mut args, &arg)?)?,
+                "--log-level" => log_filter = next_value(&mut args, &arg)?.parse()?,
+                "--log-format" => log_format = next_value(&mut args, &arg)?.parse()?,
+                "--house-account" => {
+                    house_account = Some(parse_number(&next_value(&mut args, &arg)?)?)
+                }
+                "--interest-rate" => rate = Some(parse_number(&next_value(&mut args, &arg)?)?),
+                "--day-count" => day_count = next_value(&mut args, &arg)?.parse()?,
+                "--accrue" => accrue_days = Some(parse_number(&next_value(&mut args, &arg)?)?),
+                "--auth-expiry" => auth_expiry = Some(next_value(&mut args, &arg)?.parse()?),
+                "--clock-skew" => clock_skew = parse_number(&next_value(&mut args, &arg)?)?,
+                _ if arg.starts_with("--") => return Err(anyhow!("Unknown option {}", arg)),
+                _ if file_path.is_none() => file_path = Some(PathBuf::from(arg)),
+                _ => return Err(anyhow!("Unexpected argument {}", arg)),
+            }
+        }
+
+        let command = match command.as_deref() {
+            Some("statement") => Command::Statement(StatementOptions {
+                client: client.ok_or_else(|| anyhow!("Missing --client for statement"))?,
+                from,
+                to,
+                format,
+            }),
+            Some(_) if points.is_empty() => {
+                return Err(anyhow!("Missing --at-tx or --at-line for replay"))
+            }
+            Some(_) => Command::Replay(ReplayOptions {
+                points,
+                checkpoint_every,
+            }),
+            None if dry_run => Command::DryRun,
+            None => Command::Run,
+        };
+
+        if rate.is_some_and(|rate: Decimal| rate <= Decimal::ZERO) {
+            return Err(anyhow!("Interest rate must be positive"));
+        }
+        let interest = rate.map(|rate| InterestPolicy { rate, day_count });
+        if accrue_days.is_some() && interest.is_none() {
+            return Err(anyhow!("Missing --interest-rate for --accrue"));
+        }
+        if fees_path.is_some() && house_account.is_none() {
+            return Err(anyhow!("Missing --house-account for --fees"));
+        }
+        if house_account.is_some() && fees_path.is_none() {
+            return Err(anyhow!("Missing --fees for --house-account"));
+        }
+        if chunk_size == 0 {
+            return Err(anyhow!("Chunk size must be positive"));
+        }
+
+        Ok(Self {
+            file_path: file_path.ok_or_else(|| anyhow!("Missing filename argument"))?,
+            policy,
+            auto_lock,
+            allow_unlock,
+            paranoid,
+            reconcile_path,
+            journal_path,
+            auth_expiry,
+            clock_skew,
+            limits_path,
+            rejects_path,
+            fees_path,
+            risk_rules_path,
+            alerts_path,
+            webhooks,
+            webhook_retries,
+            webhook_secret_path,
+            outbox_path,
+            metrics_address,
+            channel_capacity,
+            chunk_size,
+            log_filter,
+            log_format,
+            house_account,
+            interest,
+            accrue_days,
+            command,
+        })
+    }
+}
+
+/// Take the value of option `name` out of `args`
+fn next_value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("Missing value for {}", name))
+}
+
+/// Parse a client or a tx id
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid number {}", value))
+}
+
+/// Parse a statement output format
+fn parse_format(value: &str) -> Result<Format> {
+    match value {
+        "csv" => Ok(Format::Csv),
+        "json" => Ok(Format::Json),
+        _ => Err(anyhow!("Unknown format {}", value)),
+    }
+}
+
+/// Parse an operation name, as it appears in the `type` column of the input
+fn parse_operation(name: &str) -> Result<Operation> {
+    match name {
+        "deposit" => Ok(Operation::Deposit),
+        "withdrawal" => Ok(Operation::Withdraw),
+        "dispute" => Ok(Operation::Dispute),
+        "resolve" => Ok(Operation::Resolve),
+        "chargeback" => Ok(Operation::Chargeback),
+        "reversal" => Ok(Operation::ReverseDeposit), // Same as reversing a withdrawal for locks
+        "authorize" => Ok(Operation::Authorize),
+        "capture" => Ok(Operation::Capture),
+        "release" => Ok(Operation::Release),
+        _ => Err(anyhow!("Unknown operation {}", name)),
+    }
+}
+
+/// Parse a comma separated list of operations allowed on locked accounts
+fn parse_lock_policy(value: &str) -> Result<LockPolicy> {
+    let mut policy = LockPolicy::deny_all();
+    if value.trim() == "none" {
+        return Ok(policy);
+    }
+    for name in value.split(',') {
+        policy.allow(parse_operation(name.trim())?);
+    }
+    Ok(policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let options = Options::parse(args(&["input.csv"])).unwrap();
+        assert_eq!(options.file_path, PathBuf::from("input.csv"));
+        assert_eq!(options.policy.locked, LockPolicy::default());
+        assert!(!options.policy.allow_negative);
+        assert!(!options.auto_lock.is_enabled());
+        assert!(!options.allow_unlock);
+        assert!(!options.paranoid);
+        assert!(options.reconcile_path.is_none());
+        assert!(options.journal_path.is_none());
+        assert!(options.auth_expiry.is_none());
+        assert_eq!(options.clock_skew, 0);
+        assert!(options.limits_path.is_none());
+        assert!(options.rejects_path.is_none());
+        assert!(options.fees_path.is_none());
+        assert!(options.risk_rules_path.is_none());
+        assert!(options.alerts_path.is_none());
+        assert!(options.webhooks.is_empty());
+        assert_eq!(options.outbox_path, PathBuf::from("outbox.jsonl"));
+        assert!(options.metrics_address.is_none());
+        assert_eq!((options.channel_capacity, options.chunk_size), (4, 256));
+        assert_eq!(options.log_filter, LogFilter::default());
+        assert_eq!(options.log_format, LogFormat::Text);
+        assert!(options.house_account.is_none());
+        assert!(options.interest.is_none());
+        assert!(options.accrue_days.is_none());
+        assert_eq!(options.command, Command::Run);
+    }
+
+    #[test]
+    fn test_parse_missing_file() {
+        assert!(Options::parse(args(&[])).is_err());
+        assert!(Options::parse(args(&["a.csv", "b.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let options =
+            Options::parse(args(&["--allow-negative", "input.csv", "--paranoid"])).unwrap();
+        assert!(options.policy.allow_negative);
+        assert!(options.paranoid);
+        let options = Options::parse(args(&["--allow-unlock", "input.csv"])).unwrap();
+        assert!(options.allow_unlock);
+    }
+
+    #[test]
+    fn test_parse_dry_run() {
+        let options = Options::parse(args(&["--dry-run", "input.csv"])).unwrap();
+        assert_eq!(options.command, Command::DryRun);
+        assert!(Options::parse(args(&["replay", "--at-tx", "1", "--dry-run", "a.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_reconcile() {
+        let options = Options::parse(args(&["--reconcile", "out.csv", "input.csv"])).unwrap();
+        assert_eq!(options.reconcile_path, Some(PathBuf::from("out.csv")));
+        assert_eq!(options.file_path, PathBuf::from("input.csv"));
+        assert!(Options::parse(args(&["input.csv", "--reconcile"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_journal() {
+        let options = Options::parse(args(&["input.csv", "--journal", "journal.csv"])).unwrap();
+        assert_eq!(options.journal_path, Some(PathBuf::from("journal.csv")));
+        assert!(Options::parse(args(&["input.csv", "--journal"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let options = Options::parse(args(&["--limits", "limits.csv", "input.csv"])).unwrap();
+        assert_eq!(options.limits_path, Some(PathBuf::from("limits.csv")));
+        assert!(Options::parse(args(&["input.csv", "--limits"])).is_err());
+        let options = Options::parse(args(&["input.csv", "--rejects", "rejects.csv"])).unwrap();
+        assert_eq!(options.rejects_path, Some(PathBuf::from("rejects.csv")));
+        assert!(Options::parse(args(&["input.csv", "--rejects"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_fees() {
+        let options = Options::parse(args(&[
+            "--fees",
+            "fees.csv",
+            "--house-account",
+            "9",
+            "input.csv",
+        ]))
+        .unwrap();
+        assert_eq!(options.fees_path, Some(PathBuf::from("fees.csv")));
+        assert_eq!(options.house_account, Some(9));
+        assert!(Options::parse(args(&["input.csv", "--house-account", "x"])).is_err());
+        assert!(Options::parse(args(&["--fees", "fees.csv", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["--house-account", "9", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_auto_lock() {
+        let options = Options::parse(args(&[
+            "--max-open-disputes",
+            "3",
+            "--max-disputed",
+            "500.5",
+            "input.csv",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options.auto_lock,
+            AutoLockPolicy {
+                max_open_disputes: Some(3),
+                max_disputed: Some(Decimal::new(5005, 1)),
+            }
+        );
+        assert!(Options::parse(args(&["--max-open-disputes", "-1", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_risk_rules() {
+        let options = Options::parse(args(&[
+            "--risk-rules",
+            "rules.csv",
+            "--alerts",
+            "alerts.csv",
+            "input.csv",
+        ]))
+        .unwrap();
+        assert_eq!(options.risk_rules_path, Some(PathBuf::from("rules.csv")));
+        assert_eq!(options.alerts_path, Some(PathBuf::from("alerts.csv")));
+        assert!(Options::parse(args(&["input.csv", "--risk-rules"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_webhooks() {
+        let options = Options::parse(args(&[
+            "--webhook",
+            "http://localhost:8080/events",
+            "--webhook",
+            "http://crm/hook",
+            "--webhook-retries",
+            "5",
+            "--webhook-secret-file",
+            "secret",
+            "--outbox",
+            "events.jsonl",
+            "input.csv",
+        ]))
+        .unwrap();
+        assert_eq!(options.webhooks.len(), 2);
+        assert_eq!(options.webhooks[1].url(), "http://crm/hook");
+        assert_eq!(options.webhook_retries, 5);
+        assert_eq!(options.webhook_secret_path, Some(PathBuf::from("secret")));
+        assert_eq!(options.outbox_path, PathBuf::from("events.jsonl"));
+        assert!(Options::parse(args(&["--webhook", "https://crm/hook", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_metrics() {
+        let options = Options::parse(args(&["--metrics", "127.0.0.1:9898", "input.csv"])).unwrap();
+        assert_eq!(options.metrics_address.as_deref(), Some("127.0.0.1:9898"));
+        assert!(Options::parse(args(&["input.csv", "--metrics"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_logging() {
+        let options = Options::parse(args(&[
+            "--log-level",
+            "warn,webhook=debug",
+            "--log-format",
+            "json",
+            "input.csv",
+        ]))
+        .unwrap();
+        assert_eq!(options.log_filter, "warn,webhook=debug".parse().unwrap());
+        assert_eq!(options.log_format, LogFormat::Json);
+        assert!(Options::parse(args(&["--log-level", "loud", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["--log-format", "xml", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_channel() {
+        let options = Options::parse(args(&[
+            "--channel-capacity",
+            "16",
+            "--chunk-size",
+            "1000",
+            "input.csv",
+        ]))
+        .unwrap();
+        assert_eq!((options.channel_capacity, options.chunk_size), (16, 1000));
+        assert!(Options::parse(args(&["--chunk-size", "0", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["--channel-capacity", "-1", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_interest() {
+        let options = Options::parse(args(&[
+            "--interest-rate",
+            "2.5",
+            "--day-count",
+            "act/360",
+            "--accrue",
+            "30",
+            "input.csv",
+        ]))
+        .unwrap();
+        let expected = InterestPolicy {
+            rate: Decimal::new(25, 1),
+            day_count: DayCount::Actual360,
+        };
+        assert_eq!(options.interest, Some(expected));
+        assert_eq!(options.accrue_days, Some(Decimal::from(30)));
+
+        let options = Options::parse(args(&["--interest-rate", "1", "input.csv"])).unwrap();
+        assert_eq!(options.interest.unwrap().day_count, DayCount::Actual365);
+        assert!(Options::parse(args(&["--accrue", "30", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["--interest-rate", "0", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["--interest-rate", "-1", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["--day-count", "30/360", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_auth_expiry() {
+        let options = Options::parse(args(&["input.csv", "--auth-expiry", "100"])).unwrap();
+        assert_eq!(options.auth_expiry, Some(Span::Records(100)));
+        let options = Options::parse(args(&["input.csv", "--auth-expiry", "7d"])).unwrap();
+        assert_eq!(options.auth_expiry, Some(Span::Secs(604_800)));
+        assert!(Options::parse(args(&["input.csv", "--auth-expiry", "-1"])).is_err());
+        assert!(Options::parse(args(&["input.csv", "--auth-expiry"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_clock_skew() {
+        let options = Options::parse(args(&["--clock-skew", "60", "input.csv"])).unwrap();
+        assert_eq!(options.clock_skew, 60);
+        assert!(Options::parse(args(&["--clock-skew", "1m", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_lock_policy() {
+        let options =
+            Options::parse(args(&["--locked-allow", "deposit, resolve", "input.csv"])).unwrap();
+        let mut expected = LockPolicy::deny_all();
+        expected.allow(Operation::Deposit);
+        expected.allow(Operation::Resolve);
+        assert_eq!(options.policy.locked, expected);
+
+        let options = Options::parse(args(&["input.csv", "--locked-allow", "none"])).unwrap();
+        assert_eq!(options.policy.locked, LockPolicy::deny_all());
+        let options = Options::parse(args(&["input.csv", "--locked-allow", "reversal"])).unwrap();
+        assert!(options.policy.locked.allows(Operation::ReverseWithdrawal));
+
+        assert!(Options::parse(args(&["input.csv", "--locked-allow", "withdraw"])).is_err());
+        assert!(Options::parse(args(&["input.csv", "--locked-allow"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_statement() {
+        let options = Options::parse(args(&[
+            "statement",
+            "--client",
+            "42",
+            "--to",
+            "100",
+            "--format",
+            "json",
+            "input.csv",
+        ]))
+        .unwrap();
+        let expected = StatementOptions {
+            client: 42,
+            from: 0,
+            to: 100,
+            format: Format::Json,
+        };
+        assert_eq!(options.command, Command::Statement(expected));
+        assert_eq!(options.file_path, PathBuf::from("input.csv"));
+
+        // Client is required
+        assert!(Options::parse(args(&["statement", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["statement", "--client", "x", "input.csv"])).is_err());
+        assert!(Options::parse(args(&[
+            "statement",
+            "--client",
+            "1",
+            "--format",
+            "xml",
+            "a.csv"
+        ]))
+        .is_err());
+        // Statement options without the command
+        assert!(Options::parse(args(&["--client", "42", "input.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        let options = Options::parse(args(&[
+            "replay",
+            "--at-tx",
+            "5",
+            "input.csv",
+            "--at-line",
+            "3",
+            "--checkpoint-every",
+            "100",
+        ]))
+        .unwrap();
+        let expected = ReplayOptions {
+            points: vec![ReplayPoint::Tx(5), ReplayPoint::Line(3)],
+            checkpoint_every: 100,
+        };
+        assert_eq!(options.command, Command::Replay(expected));
+
+        // At least a point is required
+        assert!(Options::parse(args(&["replay", "input.csv"])).is_err());
+        assert!(Options::parse(args(&[
+            "replay",
+            "--at-tx",
+            "1",
+            "--checkpoint-every",
+            "0",
+            "a.csv"
+        ]))
+        .is_err());
+        // Replay options without the command
+        assert!(Options::parse(args(&["--at-tx", "1", "input.csv"])).is_err());
+        assert!(Options::parse(args(&["statement", "--at-tx", "1", "input.csv"])).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use crate::account::Operation;
+use crate::engine::Engine;
+use crate::ids::ClientId;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+/// Money that entered or left a client's account
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Flows {
+    pub deposits: Decimal,
+    pub withdrawals: Decimal,
+    pub chargebacks: Decimal,
+    pub captures: Decimal,
+    /// Fees paid, negative for the house account earning them
+    pub fees: Decimal,
+    pub interest: Decimal,
+}
+
+impl Flows {
+    /// Account for a successful `operation`. Operations moving money within the account are ignored,
+    /// and reversals are netted against the sum of what they reverse.
+    ///
+    /// Sums saturate instead of overflowing: a saturated sum will not reconcile, which is what we want.
+    pub fn add(&mut self, operation: Operation, amount: Decimal) {
+        let (sum, amount) = match operation {
+            Operation::Deposit => (&mut self.deposits, amount),
+            Operation::Withdraw => (&mut self.withdrawals, amount),
+            Operation::Chargeback => (&mut self.chargebacks, amount),
+            Operation::Capture => (&mut self.captures, amount),
+            Operation::Fee => (&mut self.fees, amount),
+            Operation::FeeIncome => (&mut self.fees, -amount),
+            Operation::Interest => (&mut self.interest, amount),
+            Operation::ReverseDeposit => (&mut self.deposits, -amount),
+            Operation::ReverseWithdrawal => (&mut self.withdrawals, -amount),
+            Operation::Dispute | Operation::Resolve | Operation::Authorize | Operation::Release => {
+                return
+            }
+        };
+        *sum = sum.saturating_add(amount);
+    }
+
+    /// What the account total should be, given the money that entered and left it
+    pub fn net(&self) -> Decimal {
+        self.deposits
+            .saturating_add(self.interest)
+            .saturating_sub(self.withdrawals)
+            .saturating_sub(self.chargebacks)
+            .saturating_sub(self.captures)
+            .saturating_sub(self.fees)
+    }
+}
+
+/// A line of the reconciliation report, for a single client or for all of them (`client` is empty)
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ReconciliationLine {
+    pub client: Option<ClientId>,
+    pub deposits: Decimal,
+    pub withdrawals: Decimal,
+    pub chargebacks: Decimal,
+    pub captures: Decimal,
+    pub fees: Decimal,
+    pub interest: Decimal,
+    pub total: Decimal,
+    pub held: Decimal,
+    pub disputed: Decimal,
+    pub authorized: Decimal,
+    pub balanced: bool,
+}
+
+impl ReconciliationLine {
+    fn new(
+        client: Option<ClientId>,
+        flows: Flows,
+        total: Decimal,
+        held: Decimal,
+        disputed: Decimal,
+        authorized: Decimal,
+    ) -> Self {
+        Self {
+            client,
+            deposits: flows.deposits,
+            withdrawals: flows.withdrawals,
+            chargebacks: flows.chargebacks,
+            captures: flows.captures,
+            fees: flows.fees,
+            interest: flows.interest,
+            total,
+            held,
+            disputed,
+            authorized,
+            balanced: flows.net() == total && Some(held) == disputed.checked_add(authorized),
+        }
+    }
+}
+
+/// Proof that the engine did not create or destroy money.
+///
+/// For every client, and for all of them together, deposits and interest minus withdrawals,
+/// chargebacks, captures and fees must equal the account total, and held funds must equal the amount under dispute or authorized.
+#[derive(Debug)]
+pub struct Reconciliation {
+    pub clients: Vec<ReconciliationLine>,
+    pub summary: ReconciliationLine,
+}
+
+impl Reconciliation {
+    /// Build the report out of the current state of `engine`
+    pub fn new(engine: &Engine) -> Self {
+        let flows = engine.get_flows();
+        let disputed = engine.get_open_disputes();
+        let authorized = engine.get_open_authorizations();
+
+        let mut clients: Vec<_> = engine
+            .get_accounts()
+            .values()
+            .map(|account| {
+                ReconciliationLine::new(
+                    Some(account.id),
+                    flows.get(&account.id).copied().unwrap_or_default(),
+                    account.total,
+                    account.held,
+                    disputed.get(&account.id).copied().unwrap_or_default(),
+                    authorized.get(&account.id).copied().unwrap_or_default(),
+                )
+            })
+            .collect();
+        clients.sort_by_key(|line| line.client);
+
+        let mut all = Flows::default();
+        let (mut total, mut held) = (Decimal::ZERO, Decimal::ZERO);
+        let (mut all_disputed, mut all_authorized) = (Decimal::ZERO, Decimal::ZERO);
+        for line in &clients {
+            all.deposits = all.deposits.saturating_add(line.deposits);
+            all.withdrawals = all.withdrawals.saturating_add(line.withdrawals);
+            all.chargebacks = all.chargebacks.saturating_add(line.chargebacks);
+            all.captures = all.captures.saturating_add(line.captures);
+            all.fees = all.fees.saturating_add(line.fees);
+            all.interest = all.interest.saturating_add(line.interest);
+            total = total.saturating_add(line.total);
+            held = held.saturating_add(line.held);
+            all_disputed = all_disputed.saturating_add(line.disputed);
+            all_authorized = all_authorized.saturating_add(line.authorized);
+        }
+        let summary = ReconciliationLine::new(None, all, total, held, all_disputed, all_authorized);
+
+        Self { clients, summary }
+    }
+
+    /// Whether every client, and the engine as a whole, reconciles
+    pub fn is_balanced(&self) -> bool {
+        self.summary.balanced && self.clients.iter().all(|line| line.balanced)
+    }
+
+    /// All the lines of the report, the summary being the last one
+    pub fn lines(&self) -> impl Iterator<Item = &ReconciliationLine> {
+        self.clients.iter().chain(std::iter::once(&self.summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::CsvWriterBuilder;
+    use crate::deser::Record;
+    use crate::ids::TxId;
+
+    fn process(
+        engine: &mut Engine,
+        command: &str,
+        client: ClientId,
+        tx: TxId,
+        amount: Option<Decimal>,
+    ) {
+        let record = Record {
+            command: command.to_string(),
+            client,
+            tx,
+            amount,
+            batch: None,
+            timestamp: None,
+        };
+        let _ = engine.process(&record);
+    }
+
+    #[test]
+    fn test_flows() {
+        let mut flows = Flows::default();
+        flows.add(Operation::Deposit, Decimal::TEN);
+        flows.add(Operation::Withdraw, Decimal::ONE);
+        flows.add(Operation::Dispute, Decimal::TWO);
+        flows.add(Operation::Resolve, Decimal::TWO);
+        flows.add(Operation::Chargeback, Decimal::TWO);
+        assert_eq!(flows.net(), Decimal::new(7, 0));
+        flows.add(Operation::ReverseWithdrawal, Decimal::ONE);
+        flows.add(Operation::ReverseDeposit, Decimal::TWO);
+        assert_eq!(flows.deposits, Decimal::new(8, 0));
+        assert_eq!(flows.withdrawals, Decimal::ZERO);
+        assert_eq!(flows.net(), Decimal::new(6, 0));
+        flows.add(Operation::Authorize, Decimal::TWO);
+        flows.add(Operation::Release, Decimal::ONE);
+        flows.add(Operation::Capture, Decimal::ONE);
+        assert_eq!(flows.captures, Decimal::ONE);
+        assert_eq!(flows.net(), Decimal::new(5, 0));
+        flows.add(Operation::Fee, Decimal::TWO);
+        assert_eq!(flows.net(), Decimal::new(3, 0));
+        flows.add(Operation::FeeIncome, Decimal::ONE);
+        assert_eq!(flows.fees, Decimal::ONE);
+        flows.add(Operation::Interest, Decimal::ONE);
+        assert_eq!(flows.net(), Decimal::new(5, 0));
+    }
+
+    #[test]
+    fn test_reconciliation_ok() {
+        let mut engine = Engine::new();
+        process(&mut engine, "deposit", 1, 1, Some(Decimal::TEN));
+        process(&mut engine, "deposit", 2, 2, Some(Decimal::TEN));
+        process(&mut engine, "withdrawal", 1, 3, Some(Decimal::ONE));
+        process(&mut engine, "withdrawal", 2, 4, Some(Decimal::ONE_HUNDRED)); // rejected
+        process(&mut engine, "dispute", 2, 2, Some(Decimal::TWO));
+        process(&mut engine, "dispute", 1, 1, Some(Decimal::new(4, 0)));
+        process(&mut engine, "chargeback", 1, 1, None);
+
+        let reconciliation = Reconciliation::new(&engine);
+        assert!(reconciliation.is_balanced());
+        assert_eq!(reconciliation.clients.len(), 2);
+        let summary = &reconciliation.summary;
+        assert_eq!(summary.deposits, Decimal::new(20, 0));
+        assert_eq!(summary.withdrawals, Decimal::ONE);
+        assert_eq!(summary.chargebacks, Decimal::new(4, 0));
+        assert_eq!(summary.total, Decimal::new(15, 0));
+        assert_eq!(summary.held, Decimal::TWO);
+        assert_eq!(summary.disputed, Decimal::TWO);
+    }
+
+    #[test]
+    fn test_reconciliation_authorizations() {
+        let mut engine = Engine::new();
+        process(&mut engine, "deposit", 1, 1, Some(Decimal::TEN));
+        process(&mut engine, "authorize", 1, 2, Some(Decimal::new(6, 0)));
+        process(&mut engine, "capture", 1, 2, Some(Decimal::TWO));
+        process(&mut engine, "dispute", 1, 1, Some(Decimal::ONE));
+
+        let reconciliation = Reconciliation::new(&engine);
+        assert!(reconciliation.is_balanced());
+        let summary = &reconciliation.summary;
+        assert_eq!(summary.captures, Decimal::TWO);
+        assert_eq!(summary.total, Decimal::new(8, 0));
+        assert_eq!(summary.held, Decimal::new(5, 0));
+        assert_eq!(summary.disputed, Decimal::ONE);
+        assert_eq!(summary.authorized, Decimal::new(4, 0));
+    }
+
+    #[test]
+    fn test_reconciliation_write() {
+        let mut engine = Engine::new();
+        process(&mut engine, "deposit", 2, 1, Some(Decimal::TEN));
+        process(&mut engine, "deposit", 1, 2, Some(Decimal::ONE));
+        let reconciliation = Reconciliation::new(&engine);
+
+        let mut wtr = CsvWriterBuilder::new(vec![]).build();
+        for line in reconciliation.lines() {
+            wtr.serialize(line).unwrap();
+        }
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            "client,deposits,withdrawals,chargebacks,captures,fees,interest,total,held,disputed,authorized,balanced\n\
+             1,1,0,0,0,0,0,1,0,0,0,true\n\
+             2,10,0,0,0,0,0,10,0,0,0,true\n\
+             ,11,0,0,0,0,0,11,0,0,0,true\n"
+        );
+    }
+
+    #[test]
+    fn test_reconciliation_mismatch() {
+        let mut engine = Engine::new();
+        process(&mut engine, "deposit", 1, 1, Some(Decimal::TEN));
+        process(&mut engine, "deposit", 2, 2, Some(Decimal::TEN));
+        let mut reconciliation = Reconciliation::new(&engine);
+        assert!(reconciliation.is_balanced());
+
+        // Money appeared out of nowhere on client 1
+        reconciliation.clients[0] = ReconciliationLine::new(
+            Some(1),
+            Flows {
+                deposits: Decimal::TEN,
+                ..Default::default()
+            },
+            Decimal::ONE_HUNDRED,
+            Decimal::ZERO,
+            Decimal::ZERO,
+            Decimal::ZERO,
+        );
+        assert!(!reconciliation.is_balanced());
+    }
+}
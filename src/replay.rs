@@ -0,0 +1,335 @@
+use crate::batch::Batches;
+use crate::csv::{self, Row};
+use crate::deser::Record;
+use crate::engine::Engine;
+use crate::ids::TxId;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// A point in the input to replay up to
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayPoint {
+    /// Right after the batch of the first record with this tx id
+    Tx(TxId),
+    /// Right after this line
+    Line(usize),
+}
+
+impl fmt::Display for ReplayPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayPoint::Tx(tx) => write!(f, "tx:{}", tx),
+            ReplayPoint::Line(line) => write!(f, "line:{}", line),
+        }
+    }
+}
+
+/// Most checkpoints kept at a time, as each is a whole engine
+const MAX_CHECKPOINTS: usize = 64;
+
+/// Records applied together: a batch, or a record on its own
+struct Unit {
+    /// Rows not applied right before the unit, as they are in a batch with a row that cannot be read
+    skipped: usize,
+    last_line: usize,
+    records: Range<usize>,
+}
+
+/// Replays an input (or any log of records) up to a given point, to answer questions like "what
+/// were client X's balances right after tx N".
+///
+/// Lines are numbered like in a normal run: from 1, header excluded, rows that cannot be read
+/// included. Batches are applied as a whole, once their last line is reached, and a batch with a
+/// row that cannot be read is not applied at all.
+///
+/// Engine snapshots are taken every `checkpoint_every` records while replaying, so that later questions
+/// restart from the closest checkpoint instead of replaying from zero every time. Once there are too
+/// many of them, every other one is dropped and they are taken half as often.
+pub struct Replay {
+    records: Vec<Record>,
+    units: Vec<Unit>,
+    lines: usize,
+    tx_index: HashMap<TxId, usize>, // Last line of the batch of the first record with a given tx id
+    checkpoints: Vec<(usize, Engine)>, // The engine after a given number of units
+    checkpoint_every: usize,
+    max_checkpoints: usize,
+}
+
+impl Replay {
+    /// Create a replay of `rows`, starting from `engine`
+    pub fn new(
+        rows: impl IntoIterator<Item = Row>,
+        engine: Engine,
+        checkpoint_every: usize,
+    ) -> Self {
+        let (mut records, mut units, mut lines, mut tx_index) = (vec![], vec![], 0, HashMap::new());
+        let mut skipped = 0;
+        let batches =
+            Batches::new(rows.into_iter(), csv::batch_of).with_unknown(csv::is_unknown_batch);
+        for rows in batches {
+            let start_line = lines;
+            lines += rows.len();
+            let invalid = rows.iter().any(|row| row.is_err());
+            let start = records.len();
+            for record in rows.into_iter().flatten() {
+                tx_index.entry(record.tx).or_insert(lines);
+                if !invalid {
+                    records.push(record);
+                }
+            }
+            if invalid {
+                skipped += lines - start_line;
+            } else {
+                units.push(Unit {
+                    skipped: std::mem::take(&mut skipped),
+                    last_line: lines,
+                    records: start..records.len(),
+                });
+            }
+        }
+        Self {
+            records,
+            units,
+            lines,
+            tx_index,
+            checkpoints: vec![(0, engine)],
+            checkpoint_every: checkpoint_every.max(1),
+            max_checkpoints: MAX_CHECKPOINTS,
+        }
+    }
+
+    /// Line that must be reached to reach `point`
+    pub fn line(&self, point: ReplayPoint) -> Result<usize> {
+        match point {
+            ReplayPoint::Tx(tx) => self
+                .tx_index
+                .get(&tx)
+                .copied()
+                .ok_or_else(|| anyhow!("Transaction not found")),
+            ReplayPoint::Line(line) if line <= self.lines => Ok(line),
+            ReplayPoint::Line(_) => Err(anyhow!("Line out of range")),
+        }
+    }
+
+    /// The engine right after `point`.
+    ///
+    /// Rejected records and batches are skipped silently, as they were already reported when first
+    /// processed.
+    pub fn at(&mut self, point: ReplayPoint) -> Result<Engine> {
+        let line = self.line(point)?;
+        let target = self.units.partition_point(|unit| unit.last_line <= line);
+
+        // Start from the closest checkpoint, taking new ones along the way
+        let checkpoint = self
+            .checkpoints
+            .partition_point(|(units, _)| *units <= target)
+            - 1;
+        let (start, engine) = &self.checkpoints[checkpoint];
+        let (start, mut engine) = (*start, engine.clone());
+        for (index, unit) in self.units.iter().enumerate().take(target).skip(start) {
+            engine.skip(unit.skipped as u64);
+            match &self.records[unit.records.clone()] {
+                [record] if record.batch.is_none() => {
+                    let _ = engine.process(record);
+                }
+                records => {
+                    let _ = engine.process_batch(records);
+                }
+            }
+            let last = self.checkpoints.last().map_or(0, |(units, _)| *units);
+            let last_records = last
+                .checked_sub(1)
+                .map_or(0, |last| self.units[last].records.end);
+            if index + 1 > last && unit.records.end >= last_records + self.checkpoint_every {
+                self.checkpoints.push((index + 1, engine.clone()));
+                if self.checkpoints.len() > self.max_checkpoints {
+                    let mut kept = 0;
+                    self.checkpoints.retain(|_| {
+                        kept += 1;
+                        kept % 2 == 1
+                    });
+                    self.checkpoint_every *= 2;
+                }
+            }
+        }
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::CsvReaderBuilder;
+    use rust_decimal::Decimal;
+    use std::io::Cursor;
+
+    fn records() -> Vec<Record> {
+        (1..=10)
+            .map(|tx| Record {
+                command: "deposit".to_string(),
+                client: 1,
+                tx,
+                amount: Some(Decimal::ONE),
+                batch: None,
+                timestamp: None,
+            })
+            .chain([Record {
+                command: "dispute".to_string(),
+                client: 1,
+                tx: 3,
+                amount: None,
+                batch: None,
+                timestamp: None,
+            }])
+            .collect()
+    }
+
+    fn total(engine: &Engine) -> Decimal {
+        engine.get_accounts()[&1].total
+    }
+
+    #[test]
+    fn test_replay_at() {
+        let mut replay = Replay::new(records().into_iter().map(Ok), Engine::new(), 3);
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(4)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        assert_eq!(replay.checkpoints.len(), 2); // After 0 and 3 records
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Tx(8)).unwrap()),
+            Decimal::new(8, 0)
+        );
+        assert_eq!(replay.checkpoints.len(), 3);
+        // Going back in time
+        assert_eq!(total(&replay.at(ReplayPoint::Tx(2)).unwrap()), Decimal::TWO);
+        assert!(replay
+            .at(ReplayPoint::Line(0))
+            .unwrap()
+            .get_accounts()
+            .is_empty());
+
+        // A tx is found by its first record, i.e. the deposit and not the dispute
+        let engine = replay.at(ReplayPoint::Tx(3)).unwrap();
+        assert_eq!(engine.get_accounts()[&1].held, Decimal::ZERO);
+        let engine = replay.at(ReplayPoint::Line(11)).unwrap();
+        assert_eq!(engine.get_accounts()[&1].held, Decimal::ONE);
+        assert_eq!(total(&engine), Decimal::TEN);
+        assert_eq!(replay.checkpoints.len(), 4);
+    }
+
+    #[test]
+    fn test_replay_batches() {
+        let data = "type,client,tx,amount,batch\n\
+                    deposit,1,1,10,\n\
+                    withdrawal,1,2,3,1\n\
+                    withdrawal,1,3,3,1\n\
+                    deposit,1,4,5,2\n\
+                    deposit,x,5,5,2\n\
+                    withdrawal,1,6,20,3\n\
+                    deposit,1,7,1,3\n\
+                    deposit,1,8,1,4\n\
+                    deposit,1\n\
+                    deposit,1,9,1,4\n\
+                    deposit,1,10,1,4,extra\n\
+                    deposit,1,11,1,4\n";
+        let mut rdr = CsvReaderBuilder::new(Cursor::new(data)).build();
+        let mut replay = Replay::new(csv::read_rows(&mut rdr), Engine::new(), 1);
+
+        // A batch is applied once its last line is reached
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(2)).unwrap()),
+            Decimal::TEN
+        );
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(3)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Tx(2)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        // A batch with a row that cannot be read is not applied, nor is a rejected one
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Tx(4)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(7)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        assert!(replay.at(ReplayPoint::Tx(5)).is_err());
+        // Nor is a batch with a malformed row in the middle, even one cut short before its batch
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Tx(9)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(12)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        assert!(replay.at(ReplayPoint::Line(13)).is_err());
+    }
+
+    #[test]
+    fn test_replay_max_checkpoints() {
+        let mut replay = Replay::new(records().into_iter().map(Ok), Engine::new(), 1);
+        replay.max_checkpoints = 4;
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(4)).unwrap()),
+            Decimal::new(4, 0)
+        );
+        let units: Vec<_> = replay.checkpoints.iter().map(|(units, _)| *units).collect();
+        assert_eq!(units, [0, 2, 4]);
+        assert_eq!(replay.checkpoint_every, 2);
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(10)).unwrap()),
+            Decimal::TEN
+        );
+        let units: Vec<_> = replay.checkpoints.iter().map(|(units, _)| *units).collect();
+        assert_eq!(units, [0, 4, 8]);
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(3)).unwrap()),
+            Decimal::new(3, 0)
+        );
+    }
+
+    #[test]
+    fn test_replay_lines() {
+        // Rows that cannot be read count, as in the logs of a normal run
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1\n\
+                    deposit,x,2,1\n\
+                    deposit,1,3,1\n\
+                    reversal,1,3,\n";
+        let mut rdr = CsvReaderBuilder::new(Cursor::new(data)).build();
+        let engine = Engine::new().with_history(true);
+        let mut replay = Replay::new(csv::read_rows(&mut rdr), engine, 1);
+        assert_eq!(replay.line(ReplayPoint::Tx(3)).unwrap(), 3);
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(2)).unwrap()),
+            Decimal::ONE
+        );
+        assert_eq!(
+            total(&replay.at(ReplayPoint::Line(3)).unwrap()),
+            Decimal::TWO
+        );
+        // So the reversal is linked by its line
+        let engine = replay.at(ReplayPoint::Line(4)).unwrap();
+        let history = engine.get_history().unwrap();
+        let reversals: Vec<_> = history
+            .statement(1, 3..=3)
+            .map(|entry| entry.reversal)
+            .collect();
+        assert_eq!(reversals, [Some(4), Some(4)]);
+    }
+
+    #[test]
+    fn test_replay_not_found() {
+        let mut replay = Replay::new(records().into_iter().map(Ok), Engine::new(), 3);
+        assert!(replay.at(ReplayPoint::Tx(42)).is_err());
+        assert!(replay.at(ReplayPoint::Line(12)).is_err());
+    }
+}
@@ -0,0 +1,514 @@
+use crate::account::Account;
+use crate::deser::Record;
+use crate::ids::{ClientId, TxId};
+use crate::window::{Moment, Span};
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::Read;
+
+/// What a rule does with the records it catches
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Apply the record anyway, raising an alert
+    Flag,
+    /// Refuse the record, raising an alert
+    Reject,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Flag => f.write_str("flag"),
+            Action::Reject => f.write_str("reject"),
+        }
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Outcome of a rule for a record. Reasons are static, so rejections can be grouped by reason.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Catch(Action, &'static str),
+}
+
+/// A fraud/risk rule, run on every record before the engine applies it.
+///
+/// Rules keep their own state, e.g. recent withdrawals, updated by `applied` with the records the
+/// engine accepted. They must be cloneable, as the engine is cloned to take snapshots.
+pub trait RiskRule: Send {
+    /// Name of the rule, for alerts
+    fn name(&self) -> &'static str;
+
+    /// Verdict on `record`, `account` being the client's account as it is now (if any) and `now`
+    /// the current moment of the client
+    fn evaluate(&self, record: &Record, account: Option<&Account>, now: Moment) -> Verdict;
+
+    /// Account for `record` having been applied, at `now`
+    fn applied(&mut self, record: &Record, now: Moment);
+
+    fn clone_box(&self) -> Box<dyn RiskRule>;
+}
+
+impl Clone for Box<dyn RiskRule> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Catches withdrawals beyond `count` within `window`
+#[derive(Clone, Debug)]
+pub struct Velocity {
+    pub action: Action,
+    pub count: usize,
+    pub window: Span,
+    withdrawals: HashMap<ClientId, VecDeque<Moment>>, // Each client's recent withdrawals
+}
+
+impl Velocity {
+    pub fn new(action: Action, count: usize, window: Span) -> Self {
+        Self {
+            action,
+            count,
+            window,
+            withdrawals: HashMap::new(),
+        }
+    }
+
+    /// Withdrawals of a client within the window, as of `now`
+    fn recent(&self, client_id: ClientId, now: Moment) -> usize {
+        self.withdrawals.get(&client_id).map_or(0, |withdrawals| {
+            withdrawals
+                .iter()
+                .filter(|at| self.window.contains(**at, now))
+                .count()
+        })
+    }
+}
+
+impl RiskRule for Velocity {
+    fn name(&self) -> &'static str {
+        "velocity"
+    }
+
+    fn evaluate(&self, record: &Record, _account: Option<&Account>, now: Moment) -> Verdict {
+        if record.command == "withdrawal" && self.recent(record.client, now) >= self.count {
+            return Verdict::Catch(self.action, "Too many withdrawals");
+        }
+        Verdict::Allow
+    }
+
+    fn applied(&mut self, record: &Record, now: Moment) {
+        if record.command != "withdrawal" {
+            return;
+        }
+        let window = self.window;
+        let withdrawals = self.withdrawals.entry(record.client).or_default();
+        while withdrawals
+            .front()
+            .is_some_and(|at| !window.contains(*at, now))
+        {
+            withdrawals.pop_front();
+        }
+        withdrawals.push_back(now);
+    }
+
+    fn clone_box(&self) -> Box<dyn RiskRule> {
+        Box::new(self.clone())
+    }
+}
+
+/// Catches withdrawals of at least `percent` of the client's last deposit, within `window` of it
+#[derive(Clone, Debug)]
+pub struct DepositWithdrawal {
+    pub action: Action,
+    pub percent: Decimal,
+    pub window: Span,
+    deposits: HashMap<ClientId, (Moment, Decimal)>, // Last deposit of each client, with its amount
+}
+
+impl DepositWithdrawal {
+    pub fn new(action: Action, percent: Decimal, window: Span) -> Self {
+        Self {
+            action,
+            percent,
+            window,
+            deposits: HashMap::new(),
+        }
+    }
+}
+
+impl RiskRule for DepositWithdrawal {
+    fn name(&self) -> &'static str {
+        "deposit_withdrawal"
+    }
+
+    fn evaluate(&self, record: &Record, _account: Option<&Account>, now: Moment) -> Verdict {
+        if record.command != "withdrawal" {
+            return Verdict::Allow;
+        }
+        let (Some(&(at, deposit)), Some(amount)) =
+            (self.deposits.get(&record.client), record.amount)
+        else {
+            return Verdict::Allow;
+        };
+        let threshold = deposit.saturating_mul(self.percent) / Decimal::ONE_HUNDRED;
+        if self.window.contains(at, now) && amount >= threshold {
+            return Verdict::Catch(self.action, "Deposit withdrawn right away");
+        }
+        Verdict::Allow
+    }
+
+    fn applied(&mut self, record: &Record, now: Moment) {
+        if let ("deposit", Some(amount)) = (record.command.as_str(), record.amount) {
+            self.deposits.insert(record.client, (now, amount));
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn RiskRule> {
+        Box::new(self.clone())
+    }
+}
+
+/// Catches disputes bringing a client's disputes beyond `percent` of its deposits, once it made at
+/// least `min_deposits`
+#[derive(Clone, Debug)]
+pub struct DisputeRate {
+    pub action: Action,
+    pub percent: Decimal,
+    pub min_deposits: usize,
+    counts: HashMap<ClientId, (usize, usize)>, // Deposits and disputes of each client
+}
+
+impl DisputeRate {
+    pub fn new(action: Action, percent: Decimal, min_deposits: usize) -> Self {
+        Self {
+            action,
+            percent,
+            min_deposits,
+            counts: HashMap::new(),
+        }
+    }
+}
+
+impl RiskRule for DisputeRate {
+    fn name(&self) -> &'static str {
+        "dispute_rate"
+    }
+
+    fn evaluate(&self, record: &Record, _account: Option<&Account>, _now: Moment) -> Verdict {
+        if record.command != "dispute" {
+            return Verdict::Allow;
+        }
+        let (deposits, disputes) = self.counts.get(&record.client).copied().unwrap_or_default();
+        if deposits == 0 || deposits < self.min_deposits {
+            return Verdict::Allow;
+        }
+        let rate = Decimal::from(disputes + 1) * Decimal::ONE_HUNDRED / Decimal::from(deposits);
+        if rate > self.percent {
+            return Verdict::Catch(self.action, "Dispute rate exceeded");
+        }
+        Verdict::Allow
+    }
+
+    fn applied(&mut self, record: &Record, _now: Moment) {
+        let counts = self.counts.entry(record.client).or_default();
+        match record.command.as_str() {
+            "deposit" => counts.0 += 1,
+            "dispute" => counts.1 += 1,
+            _ => {}
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn RiskRule> {
+        Box::new(self.clone())
+    }
+}
+
+/// A row of the risk rules file. The meaning of the parameters depends on the rule.
+#[derive(Debug, Deserialize)]
+struct RuleRow {
+    rule: String,
+    action: String,
+    count: Option<usize>,
+    window: Option<Span>,
+    percent: Option<Decimal>,
+}
+
+/// A record caught by a rule
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Alert {
+    pub tx: TxId,
+    pub client: ClientId,
+    #[serde(rename = "type")]
+    pub command: String,
+    pub rule: &'static str,
+    pub action: Action,
+    pub reason: &'static str,
+}
+
+/// Risk rules run before the engine applies a record, and the alerts they raised
+#[derive(Clone, Default)]
+pub struct Risk {
+    rules: Vec<Box<dyn RiskRule>>,
+    alerts: Vec<Alert>,
+}
+
+impl Risk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule, run after the ones added before
+    pub fn with_rule(mut self, rule: Box<dyn RiskRule>) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Read rules from CSV, one per row (`rule`, `action`, `count`, `window`, `percent`):
+    /// - `velocity`: more than `count` withdrawals within `window`.
+    /// - `deposit_withdrawal`: a withdrawal of at least `percent` (default 100) of the last deposit,
+    ///   within `window` of it.
+    /// - `dispute_rate`: disputes beyond `percent` of the deposits, from `count` (default 1) deposits on.
+    ///
+    /// Windows are either a number of the client's records or a duration, see `Span`. The action is
+    /// either `flag` or `reject`.
+    pub fn from_reader<R: Read>(mut rdr: csv::Reader<R>) -> Result<Self> {
+        let mut risk = Self::new();
+        for row in rdr.deserialize() {
+            let row: RuleRow = row?;
+            let action = match row.action.as_str() {
+                "flag" => Action::Flag,
+                "reject" => Action::Reject,
+                _ => return Err(anyhow!("Unknown action {}", row.action)),
+            };
+            let window = || {
+                row.window
+                    .ok_or_else(|| anyhow!("Missing window for {}", row.rule))
+            };
+            let rule: Box<dyn RiskRule> = match row.rule.as_str() {
+                "velocity" => {
+                    let count = row
+                        .count
+                        .ok_or_else(|| anyhow!("Missing count for {}", row.rule))?;
+                    Box::new(Velocity::new(action, count, window()?))
+                }
+                "deposit_withdrawal" => Box::new(DepositWithdrawal::new(
+                    action,
+                    row.percent.unwrap_or(Decimal::ONE_HUNDRED),
+                    window()?,
+                )),
+                "dispute_rate" => {
+                    let percent = row
+                        .percent
+                        .ok_or_else(|| anyhow!("Missing percent for {}", row.rule))?;
+                    Box::new(DisputeRate::new(action, percent, row.count.unwrap_or(1)))
+                }
+                _ => return Err(anyhow!("Unknown rule {}", row.rule)),
+            };
+            risk = risk.with_rule(rule);
+        }
+        Ok(risk)
+    }
+
+    /// Run the rules on `record`, raising an alert for every rule catching it.
+    ///
+    /// Fails if any rule rejects it.
+    pub fn check(&mut self, record: &Record, account: Option<&Account>, now: Moment) -> Result<()> {
+        let mut rejection = None;
+        for rule in &self.rules {
+            let Verdict::Catch(action, reason) = rule.evaluate(record, account, now) else {
+                continue;
+            };
+            self.alerts.push(Alert {
+                tx: record.tx,
+                client: record.client,
+                command: record.command.clone(),
+                rule: rule.name(),
+                action,
+                reason,
+            });
+            if action == Action::Reject && rejection.is_none() {
+                rejection = Some((rule.name(), reason));
+            }
+        }
+        match rejection {
+            Some((name, reason)) => {
+                Err(anyhow!(reason).context(format!("Rejected by risk rule {}", name)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Let the rules account for `record` having been applied, at `now`
+    pub fn applied(&mut self, record: &Record, now: Moment) {
+        for rule in &mut self.rules {
+            rule.applied(record, now);
+        }
+    }
+
+    /// All the alerts raised so far, in order
+    pub fn alerts(&self) -> &[Alert] {
+        &self.alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::CsvReaderBuilder;
+
+    fn at(records: u64) -> Moment {
+        Moment {
+            records,
+            timestamp: None,
+        }
+    }
+
+    fn record(command: &str, tx: TxId, amount: Option<Decimal>) -> Record {
+        Record {
+            command: command.to_string(),
+            client: 1,
+            tx,
+            amount,
+            batch: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_velocity() {
+        let mut rule = Velocity::new(Action::Reject, 2, Span::Records(3));
+        let withdrawal = record("withdrawal", 1, Some(Decimal::ONE));
+        for clock in [1, 2] {
+            assert_eq!(rule.evaluate(&withdrawal, None, at(clock)), Verdict::Allow);
+            rule.applied(&withdrawal, at(clock));
+        }
+        assert_eq!(
+            rule.evaluate(&withdrawal, None, at(3)),
+            Verdict::Catch(Action::Reject, "Too many withdrawals")
+        );
+        assert_eq!(
+            rule.evaluate(&record("deposit", 2, Some(Decimal::ONE)), None, at(3)),
+            Verdict::Allow
+        );
+        // The first withdrawal left the window
+        assert_eq!(rule.evaluate(&withdrawal, None, at(4)), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_velocity_time() {
+        let mut rule = Velocity::new(Action::Flag, 1, Span::Secs(60));
+        let at = |records, timestamp: &str| Moment {
+            records,
+            timestamp: Some(timestamp.parse().unwrap()),
+        };
+        let withdrawal = record("withdrawal", 1, Some(Decimal::ONE));
+        rule.applied(&withdrawal, at(1, "1000"));
+        let caught = Verdict::Catch(Action::Flag, "Too many withdrawals");
+        assert_eq!(rule.evaluate(&withdrawal, None, at(50, "1059")), caught);
+        assert_eq!(
+            rule.evaluate(&withdrawal, None, at(2, "1060")),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn test_deposit_withdrawal() {
+        let mut rule = DepositWithdrawal::new(Action::Flag, Decimal::new(90, 0), Span::Records(2));
+        rule.applied(&record("deposit", 1, Some(Decimal::ONE_HUNDRED)), at(1));
+        let caught = Verdict::Catch(Action::Flag, "Deposit withdrawn right away");
+        let withdrawal = record("withdrawal", 2, Some(Decimal::new(95, 0)));
+        assert_eq!(rule.evaluate(&withdrawal, None, at(2)), caught);
+        assert_eq!(rule.evaluate(&withdrawal, None, at(3)), Verdict::Allow);
+        let withdrawal = record("withdrawal", 2, Some(Decimal::new(50, 0)));
+        assert_eq!(rule.evaluate(&withdrawal, None, at(2)), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_dispute_rate() {
+        let mut rule = DisputeRate::new(Action::Reject, Decimal::new(50, 0), 2);
+        let dispute = record("dispute", 1, None);
+        assert_eq!(rule.evaluate(&dispute, None, at(1)), Verdict::Allow);
+        rule.applied(&record("deposit", 1, Some(Decimal::ONE)), at(1));
+        // Not enough deposits yet
+        assert_eq!(rule.evaluate(&dispute, None, at(2)), Verdict::Allow);
+        rule.applied(&record("deposit", 2, Some(Decimal::ONE)), at(2));
+        assert_eq!(rule.evaluate(&dispute, None, at(3)), Verdict::Allow);
+        rule.applied(&dispute, at(3));
+        assert_eq!(
+            rule.evaluate(&dispute, None, at(4)),
+            Verdict::Catch(Action::Reject, "Dispute rate exceeded")
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let mut risk = Risk::new()
+            .with_rule(Box::new(Velocity::new(Action::Flag, 0, Span::Records(10))))
+            .with_rule(Box::new(DepositWithdrawal::new(
+                Action::Reject,
+                Decimal::ONE_HUNDRED,
+                Span::Records(10),
+            )));
+        let deposit = record("deposit", 1, Some(Decimal::TEN));
+        risk.check(&deposit, None, at(1)).unwrap();
+        risk.applied(&deposit, at(1));
+        let err = risk
+            .check(&record("withdrawal", 2, Some(Decimal::TEN)), None, at(2))
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Rejected by risk rule deposit_withdrawal: Deposit withdrawn right away"
+        );
+        risk.check(&record("withdrawal", 3, Some(Decimal::ONE)), None, at(3))
+            .unwrap();
+        let alerts: Vec<_> = risk
+            .alerts()
+            .iter()
+            .map(|alert| (alert.tx, alert.rule, alert.action))
+            .collect();
+        assert_eq!(
+            alerts,
+            vec![
+                (2, "velocity", Action::Flag),
+                (2, "deposit_withdrawal", Action::Reject),
+                (3, "velocity", Action::Flag),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let data = "rule,action,count,window,percent\n\
+                    velocity,flag,5,24h,\n\
+                    deposit_withdrawal,reject,,10,\n\
+                    dispute_rate,flag,,,20\n";
+        let rdr = CsvReaderBuilder::new(data.as_bytes()).build();
+        let risk = Risk::from_reader(rdr).unwrap();
+        let names: Vec<_> = risk.rules.iter().map(|rule| rule.name()).collect();
+        assert_eq!(
+            names,
+            vec!["velocity", "deposit_withdrawal", "dispute_rate"]
+        );
+
+        for data in [
+            "rule,action,count,window,percent\nvelocity,flag,5,,\n",
+            "rule,action,count,window,percent\nvelocity,block,5,10,\n",
+            "rule,action,count,window,percent\nvelocity,flag,5,1w,\n",
+            "rule,action,count,window,percent\ndispute_rate,flag,,,\n",
+            "rule,action,count,window,percent\nnight_owl,flag,,,\n",
+        ] {
+            let rdr = CsvReaderBuilder::new(data.as_bytes()).build();
+            assert!(Risk::from_reader(rdr).is_err(), "{}", data);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of the proleptic Gregorian calendar, as (year, month, day), of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153; // Starting from March
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parse exactly `len` ASCII digits at the start of `value`, returning the number and the rest
+fn parse_digits(value: &str, len: usize) -> Option<(i64, &str)> {
+    let digits = value.get(..len)?;
+    if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((digits.parse().ok()?, &value[len..]))
+}
+
+/// Point in time, in milliseconds since the Unix epoch (UTC)
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    /// Current time of the system clock
+    pub fn now() -> Self {
+        let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX),
+            Err(err) => -i64::try_from(err.duration().as_millis()).unwrap_or(i64::MAX),
+        };
+        Self(millis)
+    }
+
+    /// Milliseconds since the Unix epoch
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    /// This timestamp moved `secs` seconds later, saturating
+    pub fn saturating_add_secs(&self, secs: u64) -> Self {
+        let millis = i64::try_from(secs).unwrap_or(i64::MAX).saturating_mul(1000);
+        Self(self.0.saturating_add(millis))
+    }
+
+    /// Parse an RFC 3339 date-time, e.g. `2024-03-01T12:30:00Z` or `2024-03-01T14:30:00.250+02:00`.
+    ///
+    /// Fractions of a second beyond the millisecond are truncated, leap seconds are not supported.
+    fn parse_rfc3339(value: &str) -> Option<Self> {
+        let (year, rest) = parse_digits(value, 4)?;
+        let (month, rest) = parse_digits(rest.strip_prefix('-')?, 2)?;
+        let (day, rest) = parse_digits(rest.strip_prefix('-')?, 2)?;
+        let (hour, rest) = parse_digits(rest.strip_prefix(['T', 't', ' '])?, 2)?;
+        let (minute, rest) = parse_digits(rest.strip_prefix(':')?, 2)?;
+        let (second, mut rest) = parse_digits(rest.strip_prefix(':')?, 2)?;
+        if !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+
+        let mut millis = 0;
+        if let Some(fraction) = rest.strip_prefix('.') {
+            let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+            if len == 0 {
+                return None;
+            }
+            for (position, digit) in fraction.bytes().take(3).enumerate() {
+                if position < len {
+                    millis += i64::from(digit - b'0') * 10_i64.pow(2 - position as u32);
+                }
+            }
+            rest = &fraction[len..];
+        }
+
+        let offset = match rest {
+            "Z" | "z" => 0,
+            _ => {
+                let sign = match rest.chars().next()? {
+                    '+' => 1,
+                    '-' => -1,
+                    _ => return None,
+                };
+                let (hours, rest) = parse_digits(&rest[1..], 2)?;
+                let (minutes, rest) = parse_digits(rest.strip_prefix(':')?, 2)?;
+                if !rest.is_empty() || hours > 23 || minutes > 59 {
+                    return None;
+                }
+                sign * (hours * 60 + minutes) * 60_000
+            }
+        };
+
+        let days = days_from_civil(year, month, day);
+        let time = ((hour * 60 + minute) * 60 + second) * 1000 + millis;
+        Some(Self(days * MILLIS_PER_DAY + time - offset))
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = anyhow::Error;
+
+    /// Parse either seconds since the Unix epoch or an RFC 3339 date-time
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid timestamp {}", value);
+        if !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) {
+            let secs: i64 = value.parse().map_err(|_| invalid())?;
+            return secs.checked_mul(1000).map(Self).ok_or_else(invalid);
+        }
+        Self::parse_rfc3339(value).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    /// Format as an RFC 3339 date-time in UTC, with milliseconds only if there are any
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.0.div_euclid(MILLIS_PER_DAY));
+        let time = self.0.rem_euclid(MILLIS_PER_DAY);
+        let (secs, millis) = (time / 1000, time % 1000);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )?;
+        if millis != 0 {
+            write!(f, ".{:03}", millis)?;
+        }
+        f.write_str("Z")
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_epoch() {
+        let timestamp: Timestamp = "1700000000".parse().unwrap();
+        assert_eq!(timestamp.0, 1_700_000_000_000);
+        assert_eq!(timestamp.to_string(), "2023-11-14T22:13:20Z");
+        assert_eq!("0".parse::<Timestamp>().unwrap().0, 0);
+        assert!("99999999999999999999".parse::<Timestamp>().is_err());
+        assert!("".parse::<Timestamp>().is_err());
+        assert!("-1".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        let timestamp: Timestamp = "2023-11-14T22:13:20Z".parse().unwrap();
+        assert_eq!(timestamp.0, 1_700_000_000_000);
+        let timestamp: Timestamp = "2024-02-29t01:00:00.25+02:00".parse().unwrap();
+        assert_eq!(timestamp.to_string(), "2024-02-28T23:00:00.250Z");
+        let timestamp: Timestamp = "1969-12-31 23:59:59.999999-00:30".parse().unwrap();
+        assert_eq!(timestamp.to_string(), "1970-01-01T00:29:59.999Z");
+        assert_eq!(timestamp.0, 1_799_999);
+        let timestamp: Timestamp = "2000-03-01T00:00:00Z".parse().unwrap();
+        assert_eq!(timestamp.0, 951_868_800_000);
+
+        for invalid in [
+            "2023-02-29T00:00:00Z",
+            "2023-13-01T00:00:00Z",
+            "2023-01-01T24:00:00Z",
+            "2023-01-01T00:00:60Z",
+            "2023-01-01T00:00:00",
+            "2023-01-01T00:00:00.Z",
+            "2023-01-01T00:00:00+0200",
+            "2023-01-01",
+            "23-01-01T00:00:00Z",
+            "yesterday",
+        ] {
+            assert!(invalid.parse::<Timestamp>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_civil_roundtrip() {
+        for days in [-719_468, -1, 0, 59, 11_016, 19_782, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn test_saturating_add_secs() {
+        let timestamp = Timestamp(1000);
+        assert_eq!(timestamp.saturating_add_secs(2).0, 3000);
+        assert_eq!(timestamp.saturating_add_secs(u64::MAX).0, i64::MAX);
+    }
+}
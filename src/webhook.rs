@@ -0,0 +1,608 @@
+use crate::account::Account;
+use crate::auto_lock::LockReason;
+use crate::ids::{ClientId, TxId};
+use crate::observer::{DisputeState, EngineObserver};
+use crate::timestamp::Timestamp;
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Kind of an event sent to the webhooks
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    AccountLocked,
+    DisputeOpened,
+}
+
+/// Event sent to the webhooks, as JSON
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Event {
+    /// Unique id, for receivers to drop the duplicates of retried deliveries
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub client: ClientId,
+    /// Transaction under dispute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TxId>,
+    /// Amount under dispute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Decimal>,
+    /// Why the account got locked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub created_at: Timestamp,
+}
+
+/// A line of the outbox file
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum OutboxLine {
+    Event(Event),
+    Delivered { id: String, endpoint: String },
+}
+
+/// Events not yet delivered to every endpoint, kept in an append-only file of JSON lines so that
+/// they survive restarts
+#[derive(Debug)]
+pub struct Outbox {
+    file: File,
+    endpoints: Vec<String>,
+    /// Undelivered events, with the endpoints still to deliver them to
+    events: Vec<(Event, Vec<String>)>,
+}
+
+impl Outbox {
+    /// Open the outbox at `path`, creating it if needed, and compact it down to the events still
+    /// to deliver to `endpoints`
+    pub fn open(path: &Path, endpoints: &[String]) -> Result<Self> {
+        let mut events: Vec<(Event, Vec<String>)> = Vec::new();
+        if path.exists() {
+            let file = File::open(path).context("Error opening outbox")?;
+            for line in BufReader::new(file).lines() {
+                match serde_json::from_str(&line?).context("Invalid outbox line")? {
+                    OutboxLine::Event(event) => events.push((event, endpoints.to_vec())),
+                    OutboxLine::Delivered { id, endpoint } => {
+                        if let Some((_, pending)) =
+                            events.iter_mut().find(|(event, _)| event.id == id)
+                        {
+                            pending.retain(|pending| *pending != endpoint);
+                        }
+                    }
+                }
+            }
+            events.retain(|(_, pending)| !pending.is_empty());
+        }
+
+        let compacted = PathBuf::from(format!("{}.tmp", path.display()));
+        let mut file = File::create(&compacted).context("Error compacting outbox")?;
+        for (event, pending) in &events {
+            write_line(&mut file, &OutboxLine::Event(event.clone()))?;
+            for endpoint in endpoints
+                .iter()
+                .filter(|endpoint| !pending.contains(endpoint))
+            {
+                write_line(
+                    &mut file,
+                    &OutboxLine::Delivered {
+                        id: event.id.clone(),
+                        endpoint: endpoint.clone(),
+                    },
+                )?;
+            }
+        }
+        file.sync_all()?;
+        fs::rename(&compacted, path).context("Error compacting outbox")?;
+
+        Ok(Self {
+            file: OpenOptions::new().append(true).open(path)?,
+            endpoints: endpoints.to_vec(),
+            events,
+        })
+    }
+
+    /// Durably add events to deliver to every endpoint, with a single sync
+    pub fn push_all(&mut self, events: Vec<Event>) -> Result<()> {
+        for event in &events {
+            write_line(&mut self.file, &OutboxLine::Event(event.clone()))?;
+        }
+        self.file.sync_data()?;
+        for event in events {
+            self.events.push((event, self.endpoints.clone()));
+        }
+        Ok(())
+    }
+
+    /// Durably record the delivery of event `id` to `endpoint`
+    pub fn delivered(&mut self, id: &str, endpoint: &str) -> Result<()> {
+        let line = OutboxLine::Delivered {
+            id: id.to_string(),
+            endpoint: endpoint.to_string(),
+        };
+        write_line(&mut self.file, &line)?;
+        self.file.sync_data()?;
+        for (event, pending) in &mut self.events {
+            if event.id == id {
+                pending.retain(|pending| pending != endpoint);
+            }
+        }
+        self.events.retain(|(_, pending)| !pending.is_empty());
+        Ok(())
+    }
+
+    /// Events to deliver, as (event, endpoint), oldest first
+    pub fn pending(&self) -> Vec<(Event, String)> {
+        self.events
+            .iter()
+            .flat_map(|(event, pending)| {
+                pending
+                    .iter()
+                    .map(|endpoint| (event.clone(), endpoint.clone()))
+            })
+            .collect()
+    }
+}
+
+fn write_line(file: &mut File, line: &OutboxLine) -> Result<()> {
+    let mut json = serde_json::to_vec(line)?;
+    json.push(b'\n');
+    file.write_all(&json).context("Error writing to outbox")
+}
+
+/// An `http://` URL events are posted to. HTTPS needs a TLS terminating proxy in front.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Endpoint {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        if url.starts_with("https://") {
+            return Err(anyhow!("Only http:// webhooks are supported"));
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("Invalid webhook URL {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow!("Invalid webhook URL {}", url))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("Invalid webhook URL {}", url));
+        }
+        Ok(Self {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Endpoint {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// POST `body` as JSON, failing unless the response status is 2xx
+    fn post(&self, body: &[u8], headers: &[(&str, String)], timeout: Duration) -> Result<()> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Unknown host {}", self.host))?;
+        let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.host,
+            self.port,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid HTTP response"))?;
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("HTTP status {}", status));
+        }
+        Ok(())
+    }
+}
+
+/// Hex HMAC-SHA256 of `body` with key `secret`
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Delivers the events of an outbox to the webhooks
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    endpoints: Vec<Endpoint>,
+    secret: Option<Vec<u8>>,
+    retries: u32,
+    backoff: Duration,
+    timeout: Duration,
+}
+
+impl Notifier {
+    /// Create a notifier posting to `endpoints`, retrying 3 times from a 100ms backoff
+    pub fn new(endpoints: Vec<Endpoint>) -> Self {
+        Self {
+            endpoints,
+            secret: None,
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sign the payloads with `secret`, in an `X-Signature: sha256=<hex>` header
+    pub fn with_secret(mut self, secret: Vec<u8>) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Retry a failed delivery `retries` times, waiting `backoff` before the first retry and twice
+    /// as long before each next one
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// URLs of the endpoints
+    pub fn urls(&self) -> Vec<String> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.url().to_string())
+            .collect()
+    }
+
+    /// Post `event` to `endpoint`, with retries
+    fn send(&self, endpoint: &Endpoint, event: &Event) -> Result<()> {
+        let body = serde_json::to_vec(event)?;
+        let mut headers = vec![("X-Event-Id", event.id.clone())];
+        if let Some(secret) = &self.secret {
+            headers.push(("X-Signature", format!("sha256={}", sign(secret, &body))));
+        }
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match endpoint.post(&body, &headers, self.timeout) {
+                Ok(()) => return Ok(()),
+                Err(_) if attempt < self.retries => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Deliver the pending events of `outbox`, in order. An endpoint still failing after the
+    /// retries gets its events left in the outbox, for the next delivery.
+    pub fn deliver(&self, outbox: &mut Outbox) {
+        let mut failed: Vec<&str> = Vec::new();
+        for (event, url) in outbox.pending() {
+            let Some(endpoint) = self.endpoints.iter().find(|endpoint| endpoint.url == url) else {
+                continue;
+            };
+            if failed.contains(&endpoint.url.as_str()) {
+                continue;
+            }
+            let result = self
+                .send(endpoint, &event)
+                .and_then(|()| outbox.delivered(&event.id, &url));
+            if let Err(err) = result {
+                log::warn!(
+                    event = event.id.as_str(),
+                    endpoint = url.as_str(),
+                    error:% = err;
+                    "Error delivering webhook event"
+                );
+                failed.push(&endpoint.url);
+            }
+        }
+    }
+}
+
+/// Engine observer handing an event over to the delivery thread when an account gets locked or a
+/// dispute opens. The delivery thread writes it to the outbox, so the engine never waits for a sync.
+pub struct WebhookObserver {
+    events: Sender<Event>,
+    started: i64,
+    sequence: AtomicU64,
+}
+
+impl WebhookObserver {
+    fn queue(
+        &self,
+        kind: EventKind,
+        client: ClientId,
+        tx: Option<TxId>,
+        amount: Option<Decimal>,
+        reason: Option<String>,
+    ) {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let event = Event {
+            id: format!("{}-{}", self.started, sequence),
+            kind,
+            client,
+            tx,
+            amount,
+            reason,
+            created_at: Timestamp::now(),
+        };
+        if let Err(err) = self.events.send(event) {
+            log::error!(event = err.0.id.as_str(); "Error queueing webhook event");
+        }
+    }
+}
+
+impl EngineObserver for WebhookObserver {
+    fn on_lock(&self, _before: &Account, after: &Account, reason: LockReason) {
+        let reason = Some(reason.to_string());
+        self.queue(EventKind::AccountLocked, after.id, None, None, reason);
+    }
+
+    fn on_dispute_change(
+        &self,
+        client_id: ClientId,
+        tx: TxId,
+        before: DisputeState,
+        after: DisputeState,
+    ) {
+        if let (DisputeState::Closed, DisputeState::Open(amount)) = (before, after) {
+            self.queue(
+                EventKind::DisputeOpened,
+                client_id,
+                Some(tx),
+                Some(amount),
+                None,
+            );
+        }
+    }
+}
+
+/// Start delivering the events of `outbox`, first the ones left by previous runs. Returns the
+/// observer to register with the engine, and the delivery thread, ending once the observer is
+/// dropped and the outbox delivered.
+///
+/// The events the observer hands over while the thread is busy are added to the outbox together,
+/// with a single sync.
+pub fn start(notifier: Notifier, mut outbox: Outbox) -> (Arc<WebhookObserver>, JoinHandle<()>) {
+    let (events, received): (Sender<Event>, Receiver<Event>) = mpsc::channel();
+    let observer = Arc::new(WebhookObserver {
+        events,
+        started: Timestamp::now().as_millis(),
+        sequence: AtomicU64::new(0),
+    });
+    let handle = thread::spawn(move || {
+        notifier.deliver(&mut outbox);
+        while let Ok(event) = received.recv() {
+            let events: Vec<_> = std::iter::once(event).chain(received.try_iter()).collect();
+            if let Err(err) = outbox.push_all(events) {
+                log::error!(error:% = err; "Error queueing webhook event");
+            }
+            notifier.deliver(&mut outbox);
+        }
+    });
+    (observer, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// Local HTTP server answering `statuses` in turn, returning the requests it got
+    fn stub(statuses: Vec<u16>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn event(id: &str) -> Event {
+        Event {
+            id: id.to_string(),
+            kind: EventKind::DisputeOpened,
+            client: 1,
+            tx: Some(2),
+            amount: Some(Decimal::TEN),
+            reason: None,
+            created_at: "2024-01-01T00:00:00Z".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint: Endpoint = "http://localhost:8080/a/b".parse().unwrap();
+        assert_eq!(endpoint.host, "localhost");
+        assert_eq!(endpoint.port, 8080);
+        assert_eq!(endpoint.path, "/a/b");
+        let endpoint: Endpoint = "http://example.com".parse().unwrap();
+        assert_eq!((endpoint.port, endpoint.path.as_str()), (80, "/"));
+        assert!("https://example.com".parse::<Endpoint>().is_err());
+        assert!("example.com".parse::<Endpoint>().is_err());
+        assert!("http://example.com:x/".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_outbox_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.jsonl");
+        let endpoints = vec!["http://a/".to_string(), "http://b/".to_string()];
+
+        let mut outbox = Outbox::open(&path, &endpoints).unwrap();
+        outbox.push_all(vec![event("1")]).unwrap();
+        outbox.push_all(vec![event("2")]).unwrap();
+        outbox.delivered("1", "http://a/").unwrap();
+        outbox.delivered("2", "http://a/").unwrap();
+        outbox.delivered("2", "http://b/").unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path, &endpoints).unwrap();
+        assert_eq!(
+            outbox.pending(),
+            vec![(event("1"), "http://b/".to_string())]
+        );
+        outbox.delivered("1", "http://b/").unwrap();
+        drop(outbox);
+        assert!(Outbox::open(&path, &endpoints)
+            .unwrap()
+            .pending()
+            .is_empty());
+        assert!(fs::read_to_string(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deliver_with_retries() {
+        let (url, server) = stub(vec![500, 503, 200]);
+        let dir = tempfile::tempdir().unwrap();
+        let notifier = Notifier::new(vec![url.parse().unwrap()])
+            .with_secret(b"secret".to_vec())
+            .with_retries(2, Duration::from_millis(1));
+        let mut outbox = Outbox::open(&dir.path().join("outbox"), &notifier.urls()).unwrap();
+        outbox.push_all(vec![event("1")]).unwrap();
+
+        notifier.deliver(&mut outbox);
+        assert!(outbox.pending().is_empty());
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        let body = serde_json::to_string(&event("1")).unwrap();
+        assert!(requests[2].starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(requests[2].contains("X-Event-Id: 1\r\n"));
+        let signature = format!(
+            "X-Signature: sha256={}\r\n",
+            sign(b"secret", body.as_bytes())
+        );
+        assert!(requests[2].contains(&signature));
+        assert!(requests[2].ends_with(&body));
+    }
+
+    #[test]
+    fn test_deliver_keeps_failed_events() {
+        let (url, server) = stub(vec![500, 500]);
+        let dir = tempfile::tempdir().unwrap();
+        let notifier =
+            Notifier::new(vec![url.parse().unwrap()]).with_retries(1, Duration::from_millis(1));
+        let mut outbox = Outbox::open(&dir.path().join("outbox"), &notifier.urls()).unwrap();
+        outbox.push_all(vec![event("1"), event("2")]).unwrap();
+
+        // The second event is not attempted once the endpoint failed
+        notifier.deliver(&mut outbox);
+        assert_eq!(server.join().unwrap().len(), 2);
+        assert_eq!(outbox.pending().len(), 2);
+    }
+
+    #[test]
+    fn test_observer() {
+        let (url, server) = stub(vec![200, 200]);
+        let dir = tempfile::tempdir().unwrap();
+        let notifier = Notifier::new(vec![url.parse().unwrap()]);
+        let outbox = Outbox::open(&dir.path().join("outbox"), &notifier.urls()).unwrap();
+        let (observer, delivery) = start(notifier, outbox);
+
+        let account = Account {
+            id: 3,
+            locked: true,
+            total: Decimal::ZERO,
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            overdraft: Decimal::ZERO,
+        };
+        observer.on_dispute_change(3, 7, DisputeState::Closed, DisputeState::Open(Decimal::ONE));
+        observer.on_dispute_change(3, 7, DisputeState::Open(Decimal::ONE), DisputeState::Closed);
+        observer.on_lock(&account, &account, LockReason::Chargeback);
+        drop(observer);
+        delivery.join().unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains(r#""type":"dispute_opened","client":3,"tx":7,"amount":"1""#));
+        assert!(requests[1].contains(r#""type":"account_locked","client":3,"reason":"chargeback""#));
+    }
+}
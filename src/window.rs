@@ -0,0 +1,107 @@
+use crate::timestamp::Timestamp;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// When something happened to a client, as seen by the engine
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Moment {
+    /// Records of the client processed so far, rolled back or not
+    pub records: u64,
+    /// Timestamp of the record, or of the latest one applied if it has none
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Length of a rolling window: a number of the client's own records, or a duration measured on
+/// timestamps
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Span {
+    Records(u64),
+    Secs(u64),
+}
+
+impl Span {
+    /// Whether something that happened at `since` is still within the window as of `now`.
+    ///
+    /// Without timestamps, no time passes, so nothing ever leaves a window measured in time.
+    pub fn contains(&self, since: Moment, now: Moment) -> bool {
+        match (*self, since.timestamp, now.timestamp) {
+            (Span::Records(records), _, _) => since.records.saturating_add(records) > now.records,
+            (Span::Secs(secs), Some(since), Some(now)) => since.saturating_add_secs(secs) > now,
+            (Span::Secs(_), _, _) => true,
+        }
+    }
+}
+
+impl FromStr for Span {
+    type Err = anyhow::Error;
+
+    /// Parse either a number of records, e.g. `100`, or a duration in seconds, minutes, hours or
+    /// days, e.g. `30s`, `15m`, `24h` or `7d`
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid window {}", value);
+        let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+            Some(index) => value.split_at(index),
+            None => (value, ""),
+        };
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let secs = |per_unit: u64| number.checked_mul(per_unit).ok_or_else(invalid);
+        match unit {
+            "" => Ok(Span::Records(number)),
+            "s" => Ok(Span::Secs(number)),
+            "m" => secs(60).map(Span::Secs),
+            "h" => secs(3600).map(Span::Secs),
+            "d" => secs(86_400).map(Span::Secs),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Span::Records(records) => write!(f, "{}", records),
+            Span::Secs(secs) => write!(f, "{}s", secs),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Span {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("100".parse::<Span>().unwrap(), Span::Records(100));
+        assert_eq!("30s".parse::<Span>().unwrap(), Span::Secs(30));
+        assert_eq!("15m".parse::<Span>().unwrap(), Span::Secs(900));
+        assert_eq!("24h".parse::<Span>().unwrap(), Span::Secs(86_400));
+        assert_eq!("7d".parse::<Span>().unwrap(), Span::Secs(604_800));
+        assert_eq!(Span::Secs(900).to_string(), "900s");
+        for invalid in ["", "h", "-1", "1.5h", "1w", "99999999999999999999d"] {
+            assert!(invalid.parse::<Span>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_contains() {
+        let at = |records, timestamp: Option<&str>| Moment {
+            records,
+            timestamp: timestamp.map(|timestamp| timestamp.parse().unwrap()),
+        };
+        let since = at(1, Some("1000"));
+        assert!(Span::Records(3).contains(since, at(3, Some("9999"))));
+        assert!(!Span::Records(3).contains(since, at(4, None)));
+        assert!(Span::Secs(60).contains(since, at(100, Some("1059"))));
+        assert!(!Span::Secs(60).contains(since, at(2, Some("1060"))));
+        assert!(Span::Secs(60).contains(at(1, None), at(100, Some("9999"))));
+    }
+}